@@ -0,0 +1,196 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use adana_script_core::{
+    Value,
+    primitive::{Compiler, Json, Primitive},
+};
+use anyhow::anyhow;
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tiny_http::{Request, Response};
+
+use crate::{call_function, compile_function, get_header, make_header, respond, server_header};
+
+const AUTHORIZATION: &str = "Authorization";
+const WWW_AUTHENTICATE: &str = "WWW-Authenticate";
+const DEFAULT_REALM: &str = "adana";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone)]
+pub struct RouteAuth {
+    realm: String,
+    basic: Option<Value>,
+    bearer: Option<Value>,
+    hmac_secret: Option<String>,
+}
+
+pub fn compile_auth(auth: Primitive) -> anyhow::Result<RouteAuth> {
+    let Primitive::Struct(mut auth) = auth else {
+        return Err(anyhow!(
+            "auth must be a struct (e.g struct {{basic: (user, pass) => {{..}}}}) => {auth}"
+        ));
+    };
+    let realm = match auth.remove("realm") {
+        Some(Primitive::String(realm)) => realm,
+        Some(e) => return Err(anyhow!("realm must be a string => {e}")),
+        None => DEFAULT_REALM.to_string(),
+    };
+    let basic = auth
+        .remove("basic")
+        .map(|f| compile_function(f, 2, "basic auth (user, pass)"))
+        .transpose()?;
+    let bearer = auth
+        .remove("bearer")
+        .map(|f| compile_function(f, 1, "bearer auth (token)"))
+        .transpose()?;
+    let hmac_secret = match auth.remove("hmac") {
+        Some(Primitive::String(secret)) => Some(secret),
+        Some(e) => return Err(anyhow!("hmac must be the secret (string) => {e}")),
+        None => None,
+    };
+    if basic.is_none() && bearer.is_none() && hmac_secret.is_none() {
+        return Err(anyhow!(
+            "auth must declare at least one of basic, bearer or hmac"
+        ));
+    }
+    Ok(RouteAuth {
+        realm,
+        basic,
+        bearer,
+        hmac_secret,
+    })
+}
+
+/// What the auth function returned: `true` means the credentials are valid
+/// and `default` is the principal, any other non-falsy value is the principal itself.
+fn to_principal(res: Primitive, default: &str) -> Option<Primitive> {
+    match res {
+        Primitive::EarlyReturn(r) => to_principal(*r, default),
+        Primitive::Ref(r) => r.read().ok().and_then(|r| to_principal(r.clone(), default)),
+        Primitive::Bool(true) => Some(Primitive::String(default.to_string())),
+        Primitive::Bool(false)
+        | Primitive::Null
+        | Primitive::Unit
+        | Primitive::NoReturn
+        | Primitive::Error(_) => None,
+        p => Some(p),
+    }
+}
+
+impl RouteAuth {
+    /// Returns the principal, or None if the request is not authenticated.
+    pub fn authenticate(
+        &self,
+        request: &Request,
+        compiler: &mut Box<Compiler>,
+    ) -> anyhow::Result<Option<Primitive>> {
+        let Some(authorization) = get_header(request, AUTHORIZATION) else {
+            return Ok(None);
+        };
+        let Some((scheme, credentials)) = authorization.trim().split_once(' ') else {
+            return Ok(None);
+        };
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("Basic") {
+            let Some(basic) = &self.basic else {
+                return Ok(None);
+            };
+            let Some((user, pass)) = STANDARD
+                .decode(credentials)
+                .ok()
+                .and_then(|c| String::from_utf8(c).ok())
+                .and_then(|c| {
+                    c.split_once(':')
+                        .map(|(u, p)| (u.to_string(), p.to_string()))
+                })
+            else {
+                return Ok(None);
+            };
+            let res = call_function(
+                compiler,
+                basic,
+                vec![Primitive::String(user.clone()), Primitive::String(pass)],
+            )?;
+            Ok(to_principal(res, &user))
+        } else if scheme.eq_ignore_ascii_case("Bearer") {
+            if let Some(payload) = self
+                .hmac_secret
+                .as_ref()
+                .and_then(|secret| verify(credentials, secret))
+            {
+                return Ok(Some(payload));
+            }
+            let Some(bearer) = &self.bearer else {
+                return Ok(None);
+            };
+            let res = call_function(
+                compiler,
+                bearer,
+                vec![Primitive::String(credentials.to_string())],
+            )?;
+            Ok(to_principal(res, credentials))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn unauthorized(&self, request: Request) -> anyhow::Result<u16> {
+        let mut response = Response::from_string("UNAUTHORIZED")
+            .with_status_code(401)
+            .with_header(server_header());
+        if self.basic.is_some() {
+            response.add_header(make_header(
+                WWW_AUTHENTICATE,
+                &format!(r#"Basic realm="{}""#, self.realm),
+            )?);
+        }
+        if self.bearer.is_some() || self.hmac_secret.is_some() {
+            response.add_header(make_header(
+                WWW_AUTHENTICATE,
+                &format!(r#"Bearer realm="{}""#, self.realm),
+            )?);
+        }
+        respond(request, response)
+    }
+}
+
+fn mac(secret: &str) -> anyhow::Result<HmacSha256> {
+    HmacSha256::new_from_slice(secret.as_bytes()).map_err(|e| anyhow!("invalid secret: {e}"))
+}
+
+/// Token format: `base64url(json payload).base64url(hmac-sha256(payload))`
+pub fn sign(payload: &Primitive, secret: &str) -> anyhow::Result<String> {
+    let payload = URL_SAFE_NO_PAD.encode(payload.to_json()?);
+    let mut mac = mac(secret)?;
+    mac.update(payload.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    Ok(format!("{payload}.{signature}"))
+}
+
+/// Returns the payload if the signature is valid and the token is not expired
+/// (`exp` field of the payload, in seconds since epoch).
+pub fn verify(token: &str, secret: &str) -> Option<Primitive> {
+    let (payload, signature) = token.split_once('.')?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    let mut mac = mac(secret).ok()?;
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature).ok()?;
+
+    let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    let payload = Primitive::from_json(&String::from_utf8(payload).ok()?).ok()?;
+    if let Primitive::Struct(p) = &payload {
+        if let Some(Primitive::Int(exp)) = p.get("exp") {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() as i128;
+            if now > *exp {
+                return None;
+            }
+        }
+    }
+    Some(payload)
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+
+use adana_script_core::primitive::Primitive;
+use anyhow::anyhow;
+
+use crate::{RouteTable, compile_settings};
+
+/// Route tables selected by the `Host` header, e.g `"a.local"` or `"*.a.local"`
+/// for any subdomain of a.local.
+#[derive(Debug, Default)]
+pub struct VirtualHosts {
+    exact: BTreeMap<String, RouteTable>,
+    /// (suffix including the leading dot, routes), longest suffix first
+    wildcards: Vec<(String, RouteTable)>,
+}
+
+pub fn compile_hosts(hosts: Primitive) -> anyhow::Result<VirtualHosts> {
+    let Primitive::Struct(hosts) = hosts else {
+        return Err(anyhow!(
+            "hosts must be a struct (e.g struct {{\"a.local\": struct {{routes: [...]}}}}) => {hosts}"
+        ));
+    };
+    let mut virtual_hosts = VirtualHosts::default();
+    for (host, settings) in hosts {
+        let Primitive::Struct(settings) = settings else {
+            return Err(anyhow!(
+                "settings of host {host} must be a struct => {settings}"
+            ));
+        };
+        let route_table = compile_settings(settings)?;
+        if !route_table.hosts.is_empty() {
+            return Err(anyhow!("hosts cannot be nested (host {host})"));
+        }
+        let host = host.to_lowercase();
+        if let Some(suffix) = host.strip_prefix('*') {
+            if !suffix.starts_with('.') {
+                return Err(anyhow!("wildcard host must look like *.domain => {host}"));
+            }
+            virtual_hosts
+                .wildcards
+                .push((suffix.to_string(), route_table));
+        } else {
+            virtual_hosts.exact.insert(host, route_table);
+        }
+    }
+    virtual_hosts
+        .wildcards
+        .sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+    Ok(virtual_hosts)
+}
+
+/// Strips the port of a `Host` header value, e.g `a.local:8000` or `[::1]:8000`.
+fn host_name(host: &str) -> String {
+    let host = host.trim();
+    let name = if host.starts_with('[') {
+        host.split_once(']')
+            .map(|(ipv6, _)| &host[..ipv6.len() + 1])
+            .unwrap_or(host)
+    } else {
+        host.rsplit_once(':').map(|(name, _)| name).unwrap_or(host)
+    };
+    name.trim_end_matches('.').to_lowercase()
+}
+
+impl VirtualHosts {
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcards.is_empty()
+    }
+
+    /// Returns the host pattern that matched, with its routes.
+    pub fn find(&self, host: &str) -> Option<(String, &RouteTable)> {
+        let host = host_name(host);
+        if let Some(route_table) = self.exact.get(&host) {
+            return Some((host, route_table));
+        }
+        self.wildcards
+            .iter()
+            .find(|(suffix, _)| host.ends_with(suffix.as_str()))
+            .map(|(suffix, route_table)| (format!("*{suffix}"), route_table))
+    }
+}
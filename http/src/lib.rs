@@ -6,27 +6,53 @@ use std::{
     str::FromStr,
     sync::{
         Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
         mpsc::{self, Sender},
     },
     thread::JoinHandle,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 const APPLICATION_JSON: &str = "application/json";
 const MULTIPART_FORM_DATA: &str = "multipart/form-data";
 const FORM_URL_ENCODED: &str = "application/x-www-form-urlencoded";
 const ACCEPT: &str = "Accept";
 const CONTENT_TYPE: &str = "Content-Type";
+const TEXT_HTML: &str = "text/html";
+const HOST: &str = "Host";
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Extra time given to the server thread to exit once it's done draining.
+const STOP_MARGIN: Duration = Duration::from_millis(100);
 use adana_script_core::{
-    Value,
+    BuiltInFunctionType, Value,
     primitive::{Compiler, Json, LibData, NativeFunctionCallResult, Primitive, ToNumber},
 };
+use adana_std_template_engine::render_value;
 use anyhow::anyhow;
 use multipart2::server::Multipart;
-use tiny_http::{Header, Method, Request, Response, Server};
+use tiny_http::{Header, Method, Request, Response, Server, SslConfig};
 use url::Url;
+
+mod auth;
+mod hosts;
+mod metrics;
+mod openapi;
+mod proxy;
+mod rate_limit;
+mod schema;
+mod store;
+use auth::{RouteAuth, compile_auth};
+use hosts::{VirtualHosts, compile_hosts};
+use metrics::Metrics;
+use openapi::{Docs, RouteDoc};
+use proxy::{ProxyPass, compile_proxies};
+use rate_limit::{RateLimiter, compile_rate_limit, too_many_requests};
+use schema::{Schema, ValidationError, compile_schema};
+use store::{DEFAULT_SNAPSHOT_INTERVAL, StoreFile};
+const UNIX_SOCKET_PREFIX: &str = "unix:";
 pub struct HttpServer {
     server: Server,
     server_addr: String,
+    unix_socket: Option<PathBuf>,
 }
 #[derive(Debug)]
 pub enum PathSegment {
@@ -36,19 +62,72 @@ pub enum PathSegment {
 }
 #[derive(Debug)]
 pub struct Route {
+    path: String,
     path_segments: Vec<PathSegment>,
     function: Value,
+    middlewares: Vec<Value>,
+    auth: Option<RouteAuth>,
+    rate_limit: Option<Arc<RateLimiter>>,
+    doc: RouteDoc,
+    schemas: RouteSchemas,
     method: Method,
 }
+
+impl Route {
+    /// How the route is labeled in the metrics, e.g `GET /todo/:id`.
+    fn name(&self) -> String {
+        format!("{} {}", self.method, self.path)
+    }
+}
+
+#[derive(Debug)]
+pub struct RouteSchemas {
+    body: Option<Schema>,
+    query: Option<Schema>,
+    params: Option<Schema>,
+}
 #[derive(Debug)]
 pub struct StaticServe {
     path: String,
     file_path: String,
 }
 
+/// Everything needed to dispatch a request, compiled from the settings.
+#[derive(Debug)]
+pub struct RouteTable {
+    routes: Vec<Route>,
+    statics: Vec<StaticServe>,
+    proxies: Vec<ProxyPass>,
+    rate_limit: Option<RateLimiter>,
+    docs: Option<Docs>,
+    metrics_path: Option<String>,
+    hosts: VirtualHosts,
+}
+
+pub enum ServerCommand {
+    Stop { drain_timeout: Option<Duration> },
+    Update(Box<RouteTable>),
+}
+
+struct RouteFileWatch {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    running: AtomicBool,
+    in_flight: AtomicU64,
+    requests_served: AtomicU64,
+    errors: AtomicU64,
+    metrics: Mutex<Metrics>,
+}
+
 pub struct HttpHandle {
     handle: Arc<Mutex<Option<JoinHandle<anyhow::Result<()>>>>>,
-    tx: Arc<Sender<bool>>,
+    tx: Arc<Sender<ServerCommand>>,
+    stats: Arc<ServerStats>,
+    poll_interval: Duration,
 }
 
 fn server_header() -> Header {
@@ -57,17 +136,129 @@ fn server_header() -> Header {
 
 #[unsafe(no_mangle)]
 pub fn new(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
-    let server_addr = if params.len() == 1 {
+    let server_addr = if !params.is_empty() {
         params[0].to_string()
     } else {
         "0.0.0.0:8000".into()
     };
 
-    match Server::http(&server_addr) {
+    if let Some(path) = server_addr.strip_prefix(UNIX_SOCKET_PREFIX) {
+        return new_unix(PathBuf::from(path), params.get(1));
+    }
+    if params.len() > 1 {
+        return Err(anyhow!("invalid param (e.g new(addr))"));
+    }
+
+    make_server(Server::http(&server_addr), server_addr, None)
+}
+
+#[cfg(unix)]
+fn new_unix(path: PathBuf, options: Option<&Primitive>) -> NativeFunctionCallResult {
+    use std::os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::UnixStream,
+    };
+
+    let parse_mode = |mode: &str| {
+        u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o777)
+            .ok_or_else(|| anyhow!("mode must be octal (e.g \"660\" or 660) => {mode}"))
+    };
+    let mode = match options {
+        Some(Primitive::Struct(options)) => match options.get("mode") {
+            Some(Primitive::String(mode)) => Some(parse_mode(mode)?),
+            // 660 means 0o660, like chmod
+            Some(Primitive::Int(mode)) => Some(parse_mode(&mode.to_string())?),
+            None => None,
+            Some(e) => return Err(anyhow!("invalid mode {e}")),
+        },
+        None => None,
+        Some(e) => {
+            return Err(anyhow!(
+                "second param must be a struct (e.g struct {{mode: \"660\"}}) => {e}"
+            ));
+        }
+    };
+
+    // a socket left behind by a previous run would prevent binding,
+    // but one still accepting connections belongs to a live server
+    if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+        match UnixStream::connect(&path) {
+            Ok(_) => return Err(anyhow!("{} is used by a running server", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                std::fs::remove_file(&path)?
+            }
+            Err(e) => {
+                return Err(anyhow!(
+                    "could not check whether {} is in use: {e}",
+                    path.display()
+                ));
+            }
+        }
+    }
+    let server = match mode {
+        Some(mode) => {
+            // the socket is created with the right permissions, instead of being reachable
+            // with the default ones until chmod. The umask is process wide, it's restored
+            // right after binding.
+            let previous = unsafe { libc::umask(!mode as libc::mode_t & 0o777) };
+            let server = Server::http_unix(&path);
+            unsafe { libc::umask(previous) };
+            if server.is_ok() {
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+            }
+            server
+        }
+        None => Server::http_unix(&path),
+    };
+    let server_addr = format!("{UNIX_SOCKET_PREFIX}{}", path.display());
+    make_server(server, server_addr, Some(path))
+}
+
+#[cfg(not(unix))]
+fn new_unix(_path: PathBuf, _options: Option<&Primitive>) -> NativeFunctionCallResult {
+    Err(anyhow!("unix sockets are not supported on this platform"))
+}
+
+#[unsafe(no_mangle)]
+pub fn new_tls(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    if params.len() != 2 {
+        return Err(anyhow!(
+            "invalid param (e.g new_tls(addr, struct {{cert: \"cert.pem\", key: \"key.pem\"}}))"
+        ));
+    }
+    let server_addr = params[0].to_string();
+    let Primitive::Struct(tls) = &params[1] else {
+        return Err(anyhow!(
+            "second param must be a struct (e.g struct {{cert, key}})"
+        ));
+    };
+    let read_pem = |key: &str| match tls.get(key) {
+        Some(Primitive::String(path)) => {
+            std::fs::read(path).map_err(|e| anyhow!("could not read {key} {path}: {e}"))
+        }
+        _ => Err(anyhow!("missing {key} path in tls settings")),
+    };
+    let ssl_config = SslConfig {
+        certificate: read_pem("cert")?,
+        private_key: read_pem("key")?,
+    };
+
+    make_server(Server::https(&server_addr, ssl_config), server_addr, None)
+}
+
+fn make_server(
+    server: Result<Server, Box<dyn std::error::Error + Send + Sync>>,
+    server_addr: String,
+    unix_socket: Option<PathBuf>,
+) -> NativeFunctionCallResult {
+    match server {
         Ok(server) => Ok(Primitive::LibData(LibData {
             data: Arc::new(Box::new(HttpServer {
                 server,
                 server_addr,
+                unix_socket,
             })),
         })),
         Err(e) => Err(anyhow::anyhow!("could not start server: {e}")),
@@ -91,16 +282,6 @@ pub fn start(mut params: Vec<Primitive>, mut compiler: Box<Compiler>) -> NativeF
         ));
     };
 
-    let Some(Primitive::Array(routes)) = settings.remove("routes") else {
-        return Err(anyhow!("missing routes in settings"));
-    };
-
-    let statics = if let Some(Primitive::Array(statics)) = settings.remove("static") {
-        statics
-    } else {
-        vec![]
-    };
-
     let store = match settings
         .remove("store")
         .ok_or_else(|| anyhow!("missing store struct in settings"))?
@@ -119,78 +300,376 @@ pub fn start(mut params: Vec<Primitive>, mut compiler: Box<Compiler>) -> NativeF
         _ => Primitive::Ref(Primitive::Struct(BTreeMap::new()).ref_prim()),
     };
 
-    let statics = compile_statics(statics)?;
+    let poll_interval = if let Some(poll_interval) = settings.remove("poll_interval") {
+        to_duration(&poll_interval)?
+    } else {
+        DEFAULT_POLL_INTERVAL
+    };
 
-    let routes = compile_routes(routes)?;
+    let mut watch = match settings.remove("watch") {
+        Some(Primitive::String(path)) => Some(RouteFileWatch {
+            path: PathBuf::from(path),
+            modified: None,
+        }),
+        Some(Primitive::Null) | None => None,
+        Some(e) => return Err(anyhow!("watch must be the path of the route file => {e}")),
+    };
+
+    let mut store_file = match settings.remove("store_file") {
+        Some(Primitive::String(path)) => {
+            let interval = if let Some(interval) = settings.remove("store_snapshot_interval") {
+                to_duration(&interval)?
+            } else {
+                DEFAULT_SNAPSHOT_INTERVAL
+            };
+            let mut store_file = StoreFile::new(PathBuf::from(path), interval);
+            store_file.load(&store)?;
+            Some(store_file)
+        }
+        Some(Primitive::Null) | None => None,
+        Some(e) => return Err(anyhow!("store_file must be a path => {e}")),
+    };
+
+    let mut route_table = compile_settings(settings)?;
+
+    if let Some(watch) = watch.as_mut() {
+        watch.modified = std::fs::metadata(&watch.path)?.modified().ok();
+    }
 
     let (tx, rx) = mpsc::channel();
+    let stats = Arc::new(ServerStats::default());
+    let server_stats = stats.clone();
 
     let handle: JoinHandle<anyhow::Result<()>> = std::thread::spawn(move || {
-        if let Some(server) = lib_data.data.downcast_ref::<HttpServer>() {
-            println!("server running at {}", server.server_addr);
-            loop {
-                let shutdown = rx.try_recv().ok().unwrap_or(false);
-                if shutdown {
-                    println!("server shutting down");
-                    return Ok(());
+        let Some(server) = lib_data.data.downcast_ref::<HttpServer>() else {
+            return Err(anyhow!("invalid libData value. Must be an HttpServer"));
+        };
+        let stats = server_stats;
+        stats.running.store(true, Ordering::SeqCst);
+        println!("server running at {}", server.server_addr);
+        loop {
+            if let Some(watch) = watch.as_mut() {
+                match reload_route_file(watch, &mut compiler) {
+                    Ok(Some(new_route_table)) => {
+                        println!("routes reloaded from {}", watch.path.display());
+                        route_table = new_route_table;
+                    }
+                    Ok(None) => (),
+                    Err(e) => println!("could not reload route file. {e:?}"),
                 }
-                if let Some(request) = server
-                    .server
-                    .recv_timeout(Duration::from_millis(50)) // fixme the duration could be a
-                    // parameter
-                    .ok()
-                    .flatten()
-                {
-                    match handle_request(request, &routes, &statics, &mut compiler, store.clone()) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            println!("could not process request. {e:?}")
+            }
+            if let Some(store_file) = store_file.as_mut() {
+                if let Err(e) = store_file.snapshot_if_due(&store) {
+                    println!("could not save store. {e:?}");
+                }
+            }
+            match rx.try_recv() {
+                Ok(ServerCommand::Update(new_route_table)) => {
+                    route_table = *new_route_table;
+                }
+                Ok(ServerCommand::Stop { drain_timeout }) => {
+                    println!("server shutting down");
+                    if let Some(drain_timeout) = drain_timeout {
+                        // serve the requests already accepted before giving up
+                        let deadline = Instant::now() + drain_timeout;
+                        while Instant::now() < deadline {
+                            let Some(request) = server.server.try_recv().ok().flatten() else {
+                                break;
+                            };
+                            serve_request(request, &route_table, &mut compiler, &store, &stats);
                         }
                     }
+                    stats.running.store(false, Ordering::SeqCst);
+                    // clean up the socket even if the store could not be saved
+                    let snapshot = match store_file.as_mut() {
+                        Some(store_file) => store_file.snapshot(&store),
+                        None => Ok(()),
+                    };
+                    if let Some(unix_socket) = &server.unix_socket {
+                        std::fs::remove_file(unix_socket)?;
+                    }
+                    return snapshot;
                 }
+                Err(_) => (),
+            }
+            if let Some(request) = server.server.recv_timeout(poll_interval).ok().flatten() {
+                serve_request(request, &route_table, &mut compiler, &store, &stats);
             }
-        } else {
-            Err(anyhow!("invalid libData value. Must be an HttpServer"))
         }
     });
     Ok(Primitive::LibData(LibData {
         data: Arc::new(Box::new(HttpHandle {
             handle: Arc::new(Mutex::new(Some(handle))),
             tx: Arc::new(tx),
+            stats,
+            poll_interval,
         })),
     }))
 }
 
+fn compile_settings(mut settings: BTreeMap<String, Primitive>) -> anyhow::Result<RouteTable> {
+    let hosts = settings
+        .remove("hosts")
+        .map(compile_hosts)
+        .transpose()?
+        .unwrap_or_default();
+
+    // the top level routes are the default host
+    let routes = match settings.remove("routes") {
+        Some(Primitive::Array(routes)) => routes,
+        None if !hosts.is_empty() => vec![],
+        _ => return Err(anyhow!("missing routes in settings")),
+    };
+
+    let statics = if let Some(Primitive::Array(statics)) = settings.remove("static") {
+        statics
+    } else {
+        vec![]
+    };
+
+    let proxies = if let Some(Primitive::Array(proxies)) = settings.remove("proxy") {
+        proxies
+    } else {
+        vec![]
+    };
+
+    let rate_limit = settings
+        .remove("rate_limit")
+        .map(compile_rate_limit)
+        .transpose()?;
+
+    let routes = compile_routes(routes)?;
+
+    let docs = match settings.remove("docs_path") {
+        Some(Primitive::String(path)) => Some(Docs::new(path, &routes)?),
+        Some(Primitive::Null) | None => None,
+        Some(e) => return Err(anyhow!("docs_path must be a string => {e}")),
+    };
+
+    let metrics_path = match settings.remove("metrics_path") {
+        Some(Primitive::String(path)) => Some(path.trim_end_matches('/').to_string()),
+        Some(Primitive::Null) | None => None,
+        Some(e) => return Err(anyhow!("metrics_path must be a string => {e}")),
+    };
+
+    Ok(RouteTable {
+        routes,
+        statics: compile_statics(statics)?,
+        proxies: compile_proxies(proxies)?,
+        rate_limit,
+        docs,
+        metrics_path,
+        hosts,
+    })
+}
+
+/// Read once by start, they can't be changed while the server is running.
+const STARTUP_SETTINGS: [&str; 5] = [
+    "store",
+    "poll_interval",
+    "watch",
+    "store_file",
+    "store_snapshot_interval",
+];
+
+/// Settings of update and of the watched route file.
+fn compile_runtime_settings(settings: BTreeMap<String, Primitive>) -> anyhow::Result<RouteTable> {
+    if let Some(key) = STARTUP_SETTINGS.iter().find(|k| settings.contains_key(**k)) {
+        return Err(anyhow!(
+            "{key} can only be set when the server starts, remove it from the new settings"
+        ));
+    }
+    compile_settings(settings)
+}
+
+/// Evaluates the route file again if it changed on disk since the last check.
+/// The last expression of the file must be the settings struct.
+fn reload_route_file(
+    watch: &mut RouteFileWatch,
+    compiler: &mut Box<Compiler>,
+) -> anyhow::Result<Option<RouteTable>> {
+    // the file can briefly disappear while an editor saves it
+    let Ok(metadata) = std::fs::metadata(&watch.path) else {
+        return Ok(None);
+    };
+    let modified = metadata.modified().ok();
+    if modified == watch.modified {
+        return Ok(None);
+    }
+    watch.modified = modified;
+    let script = std::fs::read_to_string(&watch.path)?;
+    let settings = compiler(
+        Value::BuiltInFunction {
+            fn_type: BuiltInFunctionType::Eval,
+            expr: Box::new(Value::Primitive(Primitive::String(script))),
+        },
+        BTreeMap::new(),
+    )?;
+    let settings = match settings {
+        Primitive::Ref(r) => r
+            .read()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))?
+            .clone(),
+        p => p,
+    };
+    let Primitive::Struct(settings) = settings else {
+        return Err(anyhow!("route file must evaluate to a settings struct"));
+    };
+    compile_runtime_settings(settings).map(Some)
+}
+
+fn serve_request(
+    request: Request,
+    route_table: &RouteTable,
+    compiler: &mut Box<Compiler>,
+    store: &Primitive,
+    stats: &ServerStats,
+) {
+    let started = Instant::now();
+    stats.in_flight.fetch_add(1, Ordering::SeqCst);
+    let is_metrics = route_table.metrics_path.as_ref().is_some_and(|path| {
+        request.method() == &Method::Get
+            && extract_path_from_url(&request)
+                .is_ok_and(|url| url.path().trim_end_matches('/') == path)
+    });
+    let res = if is_metrics {
+        serve_metrics(request, stats).map(|status| ("metrics".to_string(), status))
+    } else {
+        handle_request(request, route_table, compiler, store.clone())
+    };
+    match res {
+        Ok((route, status)) => {
+            if let Ok(mut metrics) = stats.metrics.lock() {
+                metrics.record(route, status, started.elapsed());
+            }
+        }
+        Err(e) => {
+            stats.errors.fetch_add(1, Ordering::SeqCst);
+            println!("could not process request. {e:?}")
+        }
+    }
+    stats.requests_served.fetch_add(1, Ordering::SeqCst);
+    stats.in_flight.fetch_sub(1, Ordering::SeqCst);
+}
+
+fn serve_metrics(request: Request, stats: &ServerStats) -> anyhow::Result<u16> {
+    let body = stats
+        .metrics
+        .lock()
+        .map_err(|e| anyhow!("could not acquire lock {e}"))?
+        .to_prometheus(
+            stats.in_flight.load(Ordering::SeqCst),
+            stats.errors.load(Ordering::SeqCst),
+        );
+    respond(
+        request,
+        Response::from_string(body)
+            .with_header(make_header(CONTENT_TYPE, "text/plain; version=0.0.4")?)
+            .with_header(server_header()),
+    )
+}
+
+fn to_duration(p: &Primitive) -> anyhow::Result<Duration> {
+    match p {
+        Primitive::Ref(r) => {
+            let r = r
+                .read()
+                .map_err(|e| anyhow!("could not acquire lock {e}"))?;
+            to_duration(&r)
+        }
+        Primitive::Int(ms) if *ms >= 0 => Ok(Duration::from_millis(*ms as u64)),
+        Primitive::U8(ms) => Ok(Duration::from_millis(*ms as u64)),
+        Primitive::I8(ms) if *ms >= 0 => Ok(Duration::from_millis(*ms as u64)),
+        e => Err(anyhow!(
+            "invalid duration, expected milliseconds (int) => {e}"
+        )),
+    }
+}
+
+/// Returns the name of what served the request (e.g `GET /todo/:id`), and the status code.
 fn handle_request(
     mut request: Request,
-    routes: &[Route],
-    statics: &[StaticServe],
+    route_table: &RouteTable,
     compiler: &mut Box<Compiler>,
     store: Primitive,
-) -> anyhow::Result<()> {
-    let (req, route) = match request_to_primitive(&mut request, routes) {
-        Ok((r, m)) => (r, m),
-        Err(e) => {
-            println!("err {e:?}");
-            return Err(e);
+) -> anyhow::Result<(String, u16)> {
+    // the global limit also covers the virtual hosts, which can have their own on top
+    if let Some(retry_after) = route_table
+        .rate_limit
+        .as_ref()
+        .and_then(|r| r.acquire(&request))
+    {
+        return Ok((
+            "rate_limit".to_string(),
+            too_many_requests(request, retry_after)?,
+        ));
+    }
+    if let Some((host, host_table)) =
+        get_header(&request, HOST).and_then(|host| route_table.hosts.find(&host))
+    {
+        let (name, status) = handle_request(request, host_table, compiler, store)?;
+        return Ok((format!("{host} {name}"), status));
+    }
+    if let Some((route, path_variables)) = match_route(&request, &route_table.routes)? {
+        let name = route.name();
+        if let Some(retry_after) = route.rate_limit.as_ref().and_then(|r| r.acquire(&request)) {
+            return Ok((name, too_many_requests(request, retry_after)?));
         }
-    };
-
-    if let Some(route) = route {
-        let res = compiler(
-            Value::FunctionCall {
-                parameters: Box::new(Value::BlockParen(vec![
-                    Value::Primitive(req),
-                    Value::Primitive(store),
-                ])),
-                function: Box::new(route.function.clone()),
-            },
-            BTreeMap::new(), // fixme extra ctx is probably no longer useful
-        )?;
-        handle_response(request, &res)?;
+        let mut req = request_to_primitive(&request, path_variables)?;
+        if let Some(auth) = &route.auth {
+            match auth.authenticate(&request, compiler)? {
+                Some(user) => {
+                    req.insert("user".to_string(), user);
+                }
+                None => return Ok((name, auth.unauthorized(request)?)),
+            }
+        }
+        // the body is only read once the client is allowed to call the route
+        match read_body(&mut request) {
+            Ok((body, form)) => {
+                req.insert("body".to_string(), body);
+                req.insert("form".to_string(), form);
+            }
+            Err(e) => {
+                let error = Primitive::Error(format!("invalid body: {e}"));
+                return Ok((name, handle_response(request, &error)?));
+            }
+        }
+        if let Err(ValidationError { violations }) = validate_request(route, &mut req) {
+            let response = Response::from_string(Primitive::Array(violations).to_json()?)
+                .with_status_code(422)
+                .with_header(make_header(CONTENT_TYPE, APPLICATION_JSON)?)
+                .with_header(server_header());
+            return Ok((name, respond(request, response)?));
+        }
+        // shared with the middlewares, so they can enrich the request for the handler
+        let req = Primitive::Ref(Primitive::Struct(req).ref_prim());
+        for middleware in &route.middlewares {
+            let res = call_handler(compiler, middleware, &req, &store)?;
+            if !is_pass_through(&res) {
+                return Ok((name, handle_response(request, &res)?));
+            }
+        }
+        let res = call_handler(compiler, &route.function, &req, &store)?;
+        Ok((name, handle_response(request, &res)?))
     } else {
         let url = extract_path_from_url(&request)?;
-        if let Some(st) = statics.iter().find(|s| url.path().starts_with(&s.path)) {
+        if let Some(docs) = route_table
+            .docs
+            .as_ref()
+            .filter(|d| request.method() == &Method::Get && d.matches(url.path()))
+        {
+            Ok(("docs".to_string(), docs.serve(request, url.path())?))
+        } else if let Some(proxy) = route_table.proxies.iter().find(|p| p.matches(url.path())) {
+            Ok((
+                format!("proxy {}", proxy.path),
+                proxy::forward(request, proxy)?,
+            ))
+        } else if let Some(st) = route_table
+            .statics
+            .iter()
+            .find(|s| url.path().starts_with(&s.path))
+        {
             let mut p = PathBuf::from(url.path().replacen(&st.path, &st.file_path, 1));
             if p.is_relative() {
                 p = p.canonicalize()?;
@@ -199,41 +678,81 @@ fn handle_request(
                 p.push("index.html"); // if it's a dir, index.html
             }
 
-            match File::open(&p) {
+            let status = match File::open(&p) {
                 Ok(f) => {
                     let ct = mime_guess::from_path(&p).first_or_text_plain();
-                    request
-                        .respond(
-                            Response::from_file(f)
-                                .with_header(make_header(CONTENT_TYPE, ct.as_ref())?)
-                                .with_header(server_header()),
-                        )
-                        .map_err(|e| anyhow!("could not respond: {e}"))?;
+                    respond(
+                        request,
+                        Response::from_file(f)
+                            .with_header(make_header(CONTENT_TYPE, ct.as_ref())?)
+                            .with_header(server_header()),
+                    )?
                 }
-                Err(_) => {
-                    request
-                        .respond(
-                            Response::from_string("NOT FOUND")
-                                .with_status_code(404)
-                                .with_header(server_header()),
-                        )
-                        .map_err(|e| anyhow!("could not respond: {e}"))?;
-                }
-            }
-        } else {
-            request
-                .respond(
+                Err(_) => respond(
+                    request,
                     Response::from_string("NOT FOUND")
                         .with_status_code(404)
                         .with_header(server_header()),
-                )
-                .map_err(|e| anyhow!("could not respond: {e}"))?;
+                )?,
+            };
+            Ok((format!("static {}", st.path), status))
+        } else {
+            let status = respond(
+                request,
+                Response::from_string("NOT FOUND")
+                    .with_status_code(404)
+                    .with_header(server_header()),
+            )?;
+            Ok(("not_found".to_string(), status))
         }
     }
-    Ok(())
 }
 
-fn handle_response(req: Request, res: &Primitive) -> anyhow::Result<()> {
+/// Sends the response, returns its status code.
+fn respond<R: Read>(request: Request, response: Response<R>) -> anyhow::Result<u16> {
+    let status = response.status_code().0;
+    request
+        .respond(response)
+        .map_err(|e| anyhow!("could not respond: {e}"))?;
+    Ok(status)
+}
+
+fn call_function(
+    compiler: &mut Box<Compiler>,
+    function: &Value,
+    args: Vec<Primitive>,
+) -> NativeFunctionCallResult {
+    compiler(
+        Value::FunctionCall {
+            parameters: Box::new(Value::BlockParen(
+                args.into_iter().map(Value::Primitive).collect(),
+            )),
+            function: Box::new(function.clone()),
+        },
+        BTreeMap::new(), // fixme extra ctx is probably no longer useful
+    )
+}
+
+fn call_handler(
+    compiler: &mut Box<Compiler>,
+    function: &Value,
+    req: &Primitive,
+    store: &Primitive,
+) -> NativeFunctionCallResult {
+    call_function(compiler, function, vec![req.clone(), store.clone()])
+}
+
+/// A middleware returning nothing lets the request through,
+/// anything else is the response.
+fn is_pass_through(res: &Primitive) -> bool {
+    match res {
+        Primitive::EarlyReturn(r) => is_pass_through(r),
+        Primitive::Unit | Primitive::NoReturn | Primitive::Null => true,
+        _ => false,
+    }
+}
+
+fn handle_response(req: Request, res: &Primitive) -> anyhow::Result<u16> {
     match res {
         Primitive::Ref(r) => {
             let r = r
@@ -256,14 +775,12 @@ fn handle_response(req: Request, res: &Primitive) -> anyhow::Result<()> {
                 .with_header(server_header())
                 .with_status_code(400);
                 response.add_header(make_header(CONTENT_TYPE, APPLICATION_JSON)?);
-                req.respond(response)
-                    .map_err(|e| anyhow!("cannot respond {e}"))
+                respond(req, response)
             } else {
                 let response = Response::from_string(format!("Error: {res:?}"))
                     .with_status_code(400)
                     .with_header(server_header());
-                req.respond(response)
-                    .map_err(|e| anyhow!("cannot respond {e}"))
+                respond(req, response)
             }
         }
 
@@ -272,8 +789,7 @@ fn handle_response(req: Request, res: &Primitive) -> anyhow::Result<()> {
             if let Some(accept) = get_header(&req, ACCEPT) {
                 response.add_header(make_header(CONTENT_TYPE, &accept)?);
             }
-            req.respond(response)
-                .map_err(|e| anyhow!("cannot respond {e}"))
+            respond(req, response)
         }
         v @ Primitive::Array(_)
             if get_header(&req, ACCEPT) == Some(APPLICATION_JSON.to_string())
@@ -281,8 +797,7 @@ fn handle_response(req: Request, res: &Primitive) -> anyhow::Result<()> {
         {
             let mut response = Response::from_string(v.to_json()?).with_header(server_header());
             response.add_header(make_header(CONTENT_TYPE, APPLICATION_JSON)?);
-            req.respond(response)
-                .map_err(|e| anyhow!("cannot respond {e}"))
+            respond(req, response)
         }
         Primitive::NativeLibrary(_)
         | Primitive::NativeFunction(_, _)
@@ -299,23 +814,29 @@ fn handle_response(req: Request, res: &Primitive) -> anyhow::Result<()> {
             let response = Response::from_string(format!("SERVER ERROR: BAD RETURN {res:?}"))
                 .with_status_code(500)
                 .with_header(server_header());
-            req.respond(response)
-                .map_err(|e| anyhow!("cannot respond {e}"))
+            respond(req, response)
         }
 
         Primitive::Unit => {
             let response = Response::from_string("")
                 .with_status_code(200)
                 .with_header(server_header());
-            req.respond(response)
-                .map_err(|e| anyhow!("cannot respond {e}"))
+            respond(req, response)
         }
         Primitive::Struct(res) => {
             let Some(status) = res.get("status") else {
                 return Err(anyhow!("missing status in response (e.g 200)"));
             };
 
-            let Some(body) = res.get("body") else {
+            // a template is rendered with ctx, and used as the body
+            let rendered = match res.get("template") {
+                Some(template) => Some(Primitive::String(render_value(
+                    template,
+                    res.get("ctx").unwrap_or(&Primitive::Null),
+                )?)),
+                None => None,
+            };
+            let Some(body) = rendered.as_ref().or(res.get("body")) else {
                 return Err(anyhow!("missing body in response"));
             };
 
@@ -332,12 +853,14 @@ fn handle_response(req: Request, res: &Primitive) -> anyhow::Result<()> {
                 }
             }) {
                 ct
+            } else if rendered.is_some() {
+                TEXT_HTML.to_string()
             } else if let Some(ct) = get_content_type(&req).or(get_header(&req, ACCEPT)) {
                 ct
             } else {
-                "text/html".to_string()
+                TEXT_HTML.to_string()
             };
-            let body = if ct == APPLICATION_JSON {
+            let body = if ct == APPLICATION_JSON && rendered.is_none() {
                 body.to_json()?
             } else {
                 body.to_string()
@@ -354,8 +877,10 @@ fn handle_response(req: Request, res: &Primitive) -> anyhow::Result<()> {
             for h in headers.iter().map(|(k, v)| make_header(k, &v.to_string())) {
                 response.add_header(h?);
             }
-            req.respond(response)
-                .map_err(|e| anyhow!("cannot respond {e}"))
+            if rendered.is_some() && !headers.keys().any(|k| k.eq_ignore_ascii_case(CONTENT_TYPE)) {
+                response.add_header(make_header(CONTENT_TYPE, TEXT_HTML)?);
+            }
+            respond(req, response)
         }
     }
 }
@@ -388,19 +913,12 @@ fn extract_path_from_url(req: &Request) -> anyhow::Result<Url> {
     Ok(url)
 }
 
-fn request_to_primitive<'a>(
-    req: &mut Request,
+/// Finds the route of the request, with the values of its path variables.
+fn match_route<'a>(
+    req: &Request,
     routes: &'a [Route],
-) -> anyhow::Result<(Primitive, Option<&'a Route>)> {
-    let headers = headers_to_primitive(req.headers());
-
+) -> anyhow::Result<Option<(&'a Route, BTreeMap<String, Primitive>)>> {
     let url = extract_path_from_url(req)?;
-    let query_params = Primitive::Struct(
-        url.query_pairs()
-            .map(|(k, v)| (k.to_string(), Primitive::String(v.to_string())))
-            .collect::<BTreeMap<_, _>>(),
-    );
-    let path = Primitive::String(url.path().to_string());
     let (path_variables, route) = {
         let path_segments = if url.path() == "/" || url.path().is_empty() {
             vec![PathSegment::Root]
@@ -460,26 +978,45 @@ fn request_to_primitive<'a>(
         res
     };
 
-    if route.is_none() {
-        return Ok((Primitive::Null, route));
-    }
-
-    let ct = get_content_type(req);
-    let method = Primitive::String(req.method().to_string());
+    Ok(route.map(|route| (route, path_variables)))
+}
 
-    let mut req_p = BTreeMap::from([
-        ("headers".to_string(), headers),
+/// The request passed to the handlers, without its body (see read_body).
+fn request_to_primitive(
+    req: &Request,
+    path_variables: BTreeMap<String, Primitive>,
+) -> anyhow::Result<BTreeMap<String, Primitive>> {
+    let url = extract_path_from_url(req)?;
+    let query_params = Primitive::Struct(
+        url.query_pairs()
+            .map(|(k, v)| (k.to_string(), Primitive::String(v.to_string())))
+            .collect::<BTreeMap<_, _>>(),
+    );
+    Ok(BTreeMap::from([
+        ("headers".to_string(), headers_to_primitive(req.headers())),
         ("query".to_string(), query_params),
         ("body".to_string(), Primitive::Null),
         ("form".to_string(), Primitive::Null),
-        ("path".to_string(), path),
-        ("method".to_string(), method),
+        (
+            "path".to_string(),
+            Primitive::String(url.path().to_string()),
+        ),
+        (
+            "method".to_string(),
+            Primitive::String(req.method().to_string()),
+        ),
         ("params".to_string(), Primitive::Struct(path_variables)),
-    ]);
+        ("user".to_string(), Primitive::Null),
+    ]))
+}
+
+/// Reads and parses the body, returns the body (json) and the form (multipart or url encoded).
+fn read_body(req: &mut Request) -> anyhow::Result<(Primitive, Primitive)> {
+    let ct = get_content_type(req);
     if ct == Some(APPLICATION_JSON.to_string()) {
         let mut body = String::new();
         req.as_reader().read_to_string(&mut body)?;
-        req_p.insert("body".to_string(), Primitive::from_json(&body)?);
+        Ok((Primitive::from_json(&body)?, Primitive::Null))
     } else if ct == Some(MULTIPART_FORM_DATA.to_string()) {
         let mut multipart =
             Multipart::from_request(req).map_err(|_| anyhow!("could not parse multipart"))?;
@@ -510,7 +1047,7 @@ fn request_to_primitive<'a>(
                 body.insert(key, Primitive::String(data));
             };
         }
-        req_p.insert("form".to_string(), Primitive::Struct(body));
+        Ok((Primitive::Null, Primitive::Struct(body)))
     } else if ct == Some(FORM_URL_ENCODED.to_string()) {
         let mut data = String::new();
         let mut body = BTreeMap::new();
@@ -518,10 +1055,41 @@ fn request_to_primitive<'a>(
         for (k, v) in form_urlencoded::parse(data.as_bytes()).into_owned() {
             body.insert(k, Primitive::String(v));
         }
-        req_p.insert("form".to_string(), Primitive::Struct(body));
-    };
+        Ok((Primitive::Null, Primitive::Struct(body)))
+    } else {
+        Ok((Primitive::Null, Primitive::Null))
+    }
+}
 
-    Ok((Primitive::Struct(req_p), route))
+fn validate_request(
+    route: &Route,
+    req: &mut BTreeMap<String, Primitive>,
+) -> Result<(), ValidationError> {
+    let mut violations = vec![];
+    let body_field = if matches!(req.get("body"), Some(Primitive::Null))
+        && !matches!(req.get("form"), Some(Primitive::Null))
+    {
+        "form"
+    } else {
+        "body"
+    };
+    for (field, schema) in [
+        ("params", &route.schemas.params),
+        ("query", &route.schemas.query),
+        (body_field, &route.schemas.body),
+    ] {
+        if let (Some(schema), Some(value)) = (schema, req.get_mut(field)) {
+            if field != "body" {
+                schema.coerce(value);
+            }
+            schema.validate(value, field, &mut violations);
+        }
+    }
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationError { violations })
+    }
 }
 
 fn headers_to_primitive(headers: &[Header]) -> Primitive {
@@ -562,14 +1130,95 @@ fn compile_statics(statics: Vec<Primitive>) -> anyhow::Result<Vec<StaticServe>>
     }
     Ok(static_serve)
 }
+/// Settings inherited by every route of a group (and its nested groups).
+#[derive(Clone, Default)]
+struct RouteGroup {
+    prefix: String,
+    middlewares: Vec<Value>,
+    auth: Option<RouteAuth>,
+    rate_limit: Option<Arc<RateLimiter>>,
+}
+
+fn compile_function(f: Primitive, expected_params: usize, msg: &str) -> anyhow::Result<Value> {
+    let Primitive::Function { parameters, exprs } = f else {
+        return Err(anyhow!("{msg} must be a function"));
+    };
+    if parameters.len() != expected_params {
+        return Err(anyhow!(
+            "{msg} must have exactly {expected_params} parameters"
+        ));
+    }
+    Primitive::Function { parameters, exprs }.to_value()
+}
+
+fn compile_middlewares(middlewares: Option<Primitive>) -> anyhow::Result<Vec<Value>> {
+    match middlewares {
+        Some(Primitive::Array(middlewares)) => middlewares
+            .into_iter()
+            .map(|m| compile_function(m, 2, "middleware (req, store)"))
+            .collect(),
+        Some(Primitive::Null) | None => Ok(vec![]),
+        Some(e) => Err(anyhow!("middlewares must be an array of functions => {e}")),
+    }
+}
+
+fn compile_path(path: &str) -> Vec<PathSegment> {
+    let mut segments = vec![];
+    for (pos, segment) in path.split('/').filter(|p| !p.is_empty()).enumerate() {
+        if let Some(stripped) = segment.strip_prefix(":") {
+            let segment = stripped.to_string();
+            segments.push(PathSegment::Variable {
+                position: pos,
+                name: segment,
+            })
+        } else {
+            segments.push(PathSegment::String(segment.to_string()))
+        }
+    }
+    if segments.is_empty() {
+        segments.push(PathSegment::Root);
+    }
+    segments
+}
+
 fn compile_routes(routes: Vec<Primitive>) -> anyhow::Result<Vec<Route>> {
-    fn compile_route(route: Primitive) -> anyhow::Result<Route> {
+    fn compile_route(
+        route: Primitive,
+        group: &RouteGroup,
+        compiled: &mut Vec<Route>,
+    ) -> anyhow::Result<()> {
         match route {
             Primitive::Ref(p) => {
                 let p = p
                     .read()
                     .map_err(|e| anyhow::anyhow!("could not acquire lock {e}"))?;
-                compile_route(p.clone())
+                compile_route(p.clone(), group, compiled)
+            }
+            Primitive::Struct(mut route) if route.contains_key("prefix") => {
+                let Some(Primitive::String(prefix)) = route.remove("prefix") else {
+                    return Err(anyhow!("prefix of route group must be a string"));
+                };
+                if !prefix.starts_with("/") {
+                    return Err(anyhow!("prefix of route group must start with /"));
+                }
+                let Some(Primitive::Array(routes)) = route.remove("routes") else {
+                    return Err(anyhow!("missing routes in route group {prefix}"));
+                };
+                let mut sub_group = group.clone();
+                sub_group.prefix.push_str(prefix.trim_end_matches('/'));
+                sub_group
+                    .middlewares
+                    .extend(compile_middlewares(route.remove("middlewares"))?);
+                if let Some(auth) = route.remove("auth") {
+                    sub_group.auth = Some(compile_auth(auth)?);
+                }
+                if let Some(rate_limit) = route.remove("rate_limit") {
+                    sub_group.rate_limit = Some(Arc::new(compile_rate_limit(rate_limit)?));
+                }
+                for route in routes {
+                    compile_route(route, &sub_group, compiled)?;
+                }
+                Ok(())
             }
             Primitive::Struct(mut route) => {
                 let Some(Primitive::String(path)) = route.remove("path") else {
@@ -578,86 +1227,1235 @@ fn compile_routes(routes: Vec<Primitive>) -> anyhow::Result<Vec<Route>> {
                 if !path.starts_with("/") {
                     return Err(anyhow!("path of route must start with /"));
                 }
-
-                let segments = if path.len() == 1 {
-                    vec![PathSegment::Root]
+                let path = if path == "/" && !group.prefix.is_empty() {
+                    group.prefix.clone()
                 } else {
-                    let mut segments = vec![];
-                    for (pos, segment) in path.split('/').filter(|p| !p.is_empty()).enumerate() {
-                        if let Some(stripped) = segment.strip_prefix(":") {
-                            let segment = stripped.to_string();
-                            segments.push(PathSegment::Variable {
-                                position: pos,
-                                name: segment,
-                            })
-                        } else {
-                            segments.push(PathSegment::String(segment.to_string()))
-                        }
-                    }
-                    segments
+                    format!("{}{path}", group.prefix)
                 };
 
-                let Some(Primitive::Function { parameters, exprs }) = route.remove("handler")
-                else {
+                let Some(handler) = route.remove("handler") else {
                     return Err(anyhow::anyhow!("missing handler param i route"));
                 };
-                if parameters.len() != 2 {
-                    return Err(anyhow!(
-                        "route must have exactly two parameters (req, store)"
-                    ));
-                }
+                let function = compile_function(handler, 2, "route handler (req, store)")?;
                 let Some(Primitive::String(method)) = route.remove("method") else {
                     return Err(anyhow!("missing method"));
                 };
+                let mut middlewares = group.middlewares.clone();
+                middlewares.extend(compile_middlewares(route.remove("middlewares"))?);
+                let auth = match route.remove("auth") {
+                    Some(auth) => Some(compile_auth(auth)?),
+                    None => group.auth.clone(),
+                };
+                let body_schema = route.remove("body_schema");
+                let query_schema = route.remove("query_schema");
+                let rate_limit = match route.remove("rate_limit") {
+                    Some(rate_limit) => Some(Arc::new(compile_rate_limit(rate_limit)?)),
+                    None => group.rate_limit.clone(),
+                };
 
-                Ok(Route {
-                    path_segments: segments,
-                    function: Primitive::Function { parameters, exprs }.to_value()?,
+                compiled.push(Route {
+                    path_segments: compile_path(&path),
+                    path,
+                    function,
+                    middlewares,
+                    auth,
+                    rate_limit,
+                    doc: RouteDoc {
+                        summary: route.remove("summary").map(|s| s.to_string()),
+                        query: route.remove("query").or_else(|| query_schema.clone()),
+                        body: route.remove("body").or_else(|| body_schema.clone()),
+                    },
+                    schemas: RouteSchemas {
+                        body: body_schema.map(compile_schema).transpose()?,
+                        query: query_schema.map(compile_schema).transpose()?,
+                        params: route
+                            .remove("params_schema")
+                            .map(compile_schema)
+                            .transpose()?,
+                    },
                     method: Method::from_str(&method).map_err(|e| anyhow!("bad method {e:?}"))?,
-                })
+                });
+                Ok(())
             }
             _ => Err(anyhow::anyhow!("invalid route")),
         }
     }
     let mut compiled = Vec::with_capacity(routes.len());
+    let root_group = RouteGroup::default();
     for route in routes {
-        compiled.push(compile_route(route)?);
+        compile_route(route, &root_group, &mut compiled)?;
     }
+    check_route_conflicts(&compiled)?;
+    // a static segment always wins over a path variable at the same position
+    compiled.sort_by_key(|r| {
+        r.path_segments
+            .iter()
+            .map(|s| matches!(s, PathSegment::Variable { .. }))
+            .collect::<Vec<_>>()
+    });
     Ok(compiled)
 }
 
-#[unsafe(no_mangle)]
-pub fn stop(mut params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
-    if params.len() != 1 {
-        Err(anyhow::anyhow!("invalid param"))
-    } else if let Some(Primitive::LibData(lib_data)) = params.get_mut(0) {
-        let data = lib_data.data.clone();
-
-        if let Some(server) = data.downcast_ref::<HttpHandle>() {
-            match server.handle.lock() {
-                Ok(mut http_handle) => {
-                    if let Some(handle) = http_handle.take() {
-                        server.tx.send(true)?;
-                        match handle.join() {
-                            Ok(r) => {
-                                r.map_err(|e| anyhow::anyhow!("{e}"))?;
-                                println!("server stopped");
-                                return Ok(Primitive::Unit);
-                            }
-                            Err(e) => return Err(anyhow::anyhow!("could not join handle {e:?}")),
-                        }
-                    } else {
-                        return Err(anyhow::anyhow!("server already stopped"));
+/// Two routes are ambiguous when the same request could match both of them,
+/// and neither is more specific than the other (e.g `/a/:x` and `/:y/b`).
+fn check_route_conflicts(routes: &[Route]) -> anyhow::Result<()> {
+    for (idx, route) in routes.iter().enumerate() {
+        for other in &routes[idx + 1..] {
+            if route.method != other.method
+                || route.path_segments.len() != other.path_segments.len()
+            {
+                continue;
+            }
+            let mut overlap = true;
+            let (mut route_more_specific, mut other_more_specific) = (false, false);
+            for segments in route.path_segments.iter().zip(other.path_segments.iter()) {
+                match segments {
+                    (PathSegment::Root, PathSegment::Root)
+                    | (PathSegment::Variable { .. }, PathSegment::Variable { .. }) => {}
+                    (PathSegment::String(s), PathSegment::String(s2)) if s == s2 => {}
+                    (PathSegment::String(_), PathSegment::Variable { .. }) => {
+                        route_more_specific = true
+                    }
+                    (PathSegment::Variable { .. }, PathSegment::String(_)) => {
+                        other_more_specific = true
+                    }
+                    _ => {
+                        overlap = false;
+                        break;
                     }
-                }
-                Err(_) => {
-                    return Err(anyhow::anyhow!("cannot acquire lock for handle"));
                 }
             }
-        } else {
-            return Err(anyhow::anyhow!("cannot downcast http handle"));
+            if overlap && route_more_specific == other_more_specific {
+                return Err(anyhow!(
+                    "ambiguous routes: {} {} and {} {}",
+                    route.method,
+                    route.path,
+                    other.method,
+                    other.path
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn get_http_handle(params: &[Primitive]) -> anyhow::Result<&HttpHandle> {
+    let Some(Primitive::LibData(lib_data)) = params.first() else {
+        return Err(anyhow!("invalid param"));
+    };
+    lib_data
+        .data
+        .downcast_ref::<HttpHandle>()
+        .ok_or_else(|| anyhow!("cannot downcast http handle"))
+}
+
+/// Requests are served one at a time by the server thread (`in_flight` is either 0 or 1),
+/// so draining means finishing the current request, then serving the ones already queued.
+#[unsafe(no_mangle)]
+pub fn stop(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    if params.is_empty() || params.len() > 2 {
+        return Err(anyhow!("invalid param (e.g stop(handle, [drain_timeout]))"));
+    }
+    let server = get_http_handle(&params)?;
+    let drain_timeout = params.get(1).map(to_duration).transpose()?;
+
+    let Ok(mut http_handle) = server.handle.lock() else {
+        return Err(anyhow!("cannot acquire lock for handle"));
+    };
+    let Some(handle) = http_handle.take() else {
+        return Err(anyhow!("server already stopped"));
+    };
+    // the server thread may already be gone, in which case there's nothing to notify
+    let _ = server.tx.send(ServerCommand::Stop { drain_timeout });
+
+    if let Some(drain_timeout) = drain_timeout {
+        // the server thread only notices the command at its next poll, then starts draining
+        let deadline = Instant::now() + drain_timeout + server.poll_interval + STOP_MARGIN;
+        while !handle.is_finished() {
+            if Instant::now() >= deadline {
+                *http_handle = Some(handle);
+                return Err(anyhow!(
+                    "server did not stop within {}ms",
+                    drain_timeout.as_millis()
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    match handle.join() {
+        Ok(r) => {
+            r.map_err(|e| anyhow!("{e}"))?;
+            println!("server stopped");
+            Ok(Primitive::Unit)
+        }
+        Err(e) => Err(anyhow!("could not join handle {e:?}")),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub fn update(mut params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    if params.len() != 2 {
+        return Err(anyhow!("invalid param (e.g update(handle, settings))"));
+    }
+    let Primitive::Struct(settings) = params.remove(1) else {
+        return Err(anyhow!(
+            r#"second param must be the new settings (e.g struct {{static: [], routes []}})"#
+        ));
+    };
+    let server = get_http_handle(&params)?;
+    let route_table = compile_runtime_settings(settings)?;
+    server
+        .tx
+        .send(ServerCommand::Update(Box::new(route_table)))
+        .map_err(|_| anyhow!("server is not running"))?;
+    Ok(Primitive::Unit)
+}
+
+#[unsafe(no_mangle)]
+pub fn openapi(mut params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    if params.is_empty() || params.len() > 2 {
+        return Err(anyhow!(
+            "invalid param (e.g openapi(settings, struct {{title, version}}))"
+        ));
+    }
+    let info = if params.len() == 2 {
+        match params.remove(1) {
+            Primitive::Struct(info) => info,
+            e => {
+                return Err(anyhow!(
+                    "second param must be a struct {{title, version}} => {e}"
+                ));
+            }
         }
     } else {
-        Err(anyhow::anyhow!("invalid param"))
+        BTreeMap::new()
+    };
+    let Primitive::Struct(mut settings) = params.remove(0) else {
+        return Err(anyhow!("first param must be the settings"));
+    };
+    let Some(Primitive::Array(routes)) = settings.remove("routes") else {
+        return Err(anyhow!("missing routes in settings"));
+    };
+    let routes = compile_routes(routes)?;
+    let title = info
+        .get("title")
+        .map(|t| t.to_string())
+        .unwrap_or_else(|| openapi::DEFAULT_TITLE.to_string());
+    let version = info
+        .get("version")
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| openapi::DEFAULT_VERSION.to_string());
+    Ok(Primitive::String(
+        openapi::document(&routes, &title, &version).to_json()?,
+    ))
+}
+
+#[unsafe(no_mangle)]
+pub fn sign_token(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let [payload, Primitive::String(secret)] = &params[..] else {
+        return Err(anyhow!("invalid param (e.g sign_token(payload, secret))"));
+    };
+    Ok(Primitive::String(auth::sign(payload, secret)?))
+}
+
+#[unsafe(no_mangle)]
+pub fn verify_token(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let [Primitive::String(token), Primitive::String(secret)] = &params[..] else {
+        return Err(anyhow!("invalid param (e.g verify_token(token, secret))"));
+    };
+    Ok(auth::verify(token, secret).unwrap_or(Primitive::Null))
+}
+
+#[unsafe(no_mangle)]
+pub fn status(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    if params.len() != 1 {
+        return Err(anyhow!("invalid param (e.g status(handle))"));
+    }
+    let server = get_http_handle(&params)?;
+    let stopped = server
+        .handle
+        .lock()
+        .map_err(|_| anyhow!("cannot acquire lock for handle"))?
+        .is_none();
+    let stats = &server.stats;
+    Ok(Primitive::Struct(BTreeMap::from([
+        (
+            "running".to_string(),
+            Primitive::Bool(stats.running.load(Ordering::SeqCst)),
+        ),
+        ("stopped".to_string(), Primitive::Bool(stopped)),
+        (
+            "in_flight".to_string(),
+            Primitive::Int(stats.in_flight.load(Ordering::SeqCst) as i128),
+        ),
+        (
+            "requests_served".to_string(),
+            Primitive::Int(stats.requests_served.load(Ordering::SeqCst) as i128),
+        ),
+        (
+            "errors".to_string(),
+            Primitive::Int(stats.errors.load(Ordering::SeqCst) as i128),
+        ),
+    ])))
+}
+
+#[unsafe(no_mangle)]
+pub fn metrics(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    if params.len() != 1 {
+        return Err(anyhow!("invalid param (e.g metrics(handle))"));
+    }
+    let server = get_http_handle(&params)?;
+    let stats = &server.stats;
+    let (routes, statuses) = stats
+        .metrics
+        .lock()
+        .map_err(|e| anyhow!("could not acquire lock {e}"))?
+        .to_primitive();
+    Ok(Primitive::Struct(BTreeMap::from([
+        (
+            "in_flight".to_string(),
+            Primitive::Int(stats.in_flight.load(Ordering::SeqCst) as i128),
+        ),
+        (
+            "requests_served".to_string(),
+            Primitive::Int(stats.requests_served.load(Ordering::SeqCst) as i128),
+        ),
+        (
+            "errors".to_string(),
+            Primitive::Int(stats.errors.load(Ordering::SeqCst) as i128),
+        ),
+        ("routes".to_string(), routes),
+        ("statuses".to_string(), statuses),
+    ])))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use adana_script_core::{
+        BuiltInFunctionType, Value,
+        primitive::{Compiler, Json, Primitive},
+    };
+
+    use crate::{
+        HttpServer, compile_routes, metrics, new, new_tls, openapi, sign_token, start, status,
+        stop, update, verify_token,
+    };
+
+    fn compiler() -> Box<Compiler> {
+        Box::new(|_, _| Ok(Primitive::Unit))
+    }
+
+    /// A server on a free port, with its url (e.g `http://127.0.0.1:41234`).
+    fn local_server() -> (Primitive, String) {
+        let server = new(
+            vec![Primitive::String("127.0.0.1:0".to_string())],
+            compiler(),
+        )
+        .unwrap();
+        let Primitive::LibData(lib_data) = &server else {
+            panic!("server should be lib data");
+        };
+        let addr = lib_data
+            .data
+            .downcast_ref::<HttpServer>()
+            .unwrap()
+            .server
+            .server_addr()
+            .to_ip()
+            .unwrap();
+        (server, format!("http://{addr}"))
+    }
+
+    fn route(path: &str) -> Primitive {
+        Primitive::Struct(BTreeMap::from([
+            ("path".to_string(), Primitive::String(path.to_string())),
+            ("method".to_string(), Primitive::String("GET".to_string())),
+            (
+                "handler".to_string(),
+                Primitive::Function {
+                    parameters: vec![
+                        Value::Variable("req".to_string()),
+                        Value::Variable("store".to_string()),
+                    ],
+                    exprs: vec![],
+                },
+            ),
+        ]))
+    }
+
+    #[test]
+    fn compile_route_groups() {
+        let routes = compile_routes(vec![
+            route("/users/:id"),
+            Primitive::Struct(BTreeMap::from([
+                (
+                    "prefix".to_string(),
+                    Primitive::String("/api/v1".to_string()),
+                ),
+                (
+                    "routes".to_string(),
+                    Primitive::Array(vec![route("/"), route("/users/me")]),
+                ),
+            ])),
+            route("/users/me"),
+        ])
+        .unwrap();
+        let paths = routes.iter().map(|r| r.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            ["/api/v1", "/users/me", "/api/v1/users/me", "/users/:id"]
+        );
+
+        assert!(compile_routes(vec![route("/a/:x"), route("/:y/b")]).is_err());
+        assert!(compile_routes(vec![route("/a/:x"), route("/a/:y")]).is_err());
+    }
+
+    #[test]
+    fn new_tls_self_signed() {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join("adana_std_http_test_cert.pem");
+        let key_path = dir.join("adana_std_http_test_key.pem");
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+
+        let tls = |cert: &std::path::Path| {
+            Primitive::Struct(BTreeMap::from([
+                (
+                    "cert".to_string(),
+                    Primitive::String(cert.display().to_string()),
+                ),
+                (
+                    "key".to_string(),
+                    Primitive::String(key_path.display().to_string()),
+                ),
+            ]))
+        };
+        let server = new_tls(
+            vec![
+                Primitive::String("127.0.0.1:0".to_string()),
+                tls(&cert_path),
+            ],
+            compiler(),
+        );
+        assert!(matches!(server, Ok(Primitive::LibData(_))));
+
+        let server = new_tls(
+            vec![
+                Primitive::String("127.0.0.1:0".to_string()),
+                tls(&dir.join("adana_std_http_missing.pem")),
+            ],
+            compiler(),
+        );
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn reverse_proxy() {
+        let upstream = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.server_addr().to_ip().unwrap();
+        let upstream = std::thread::spawn(move || {
+            let mut req = upstream.recv().unwrap();
+            let mut body = String::new();
+            req.as_reader().read_to_string(&mut body).unwrap();
+            let forwarded_for = req
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv("X-Forwarded-For"))
+                .map(|h| h.value.to_string());
+            let res = format!("{} {} {body} {forwarded_for:?}", req.method(), req.url());
+            req.respond(tiny_http::Response::from_string(res).with_status_code(201))
+                .unwrap();
+        });
+
+        let (server, url) = local_server();
+        let settings = Primitive::Struct(BTreeMap::from([
+            ("store".to_string(), Primitive::Struct(BTreeMap::new())),
+            ("routes".to_string(), Primitive::Array(vec![])),
+            (
+                "proxy".to_string(),
+                Primitive::Array(vec![Primitive::Struct(BTreeMap::from([
+                    ("path".to_string(), Primitive::String("/api".to_string())),
+                    (
+                        "proxy".to_string(),
+                        Primitive::String(format!("http://{upstream_addr}")),
+                    ),
+                ]))]),
+            ),
+        ]));
+        let handle = start(vec![server, settings], compiler()).unwrap();
+
+        // not under /api, the upstream would answer it otherwise
+        let Err(ureq::Error::Status(404, _)) = ureq::get(&format!("{url}/apiary")).call() else {
+            panic!("/apiary should not be proxied");
+        };
+        let res = ureq::post(&format!("{url}/api/todo?page=1"))
+            .send_string("hello")
+            .unwrap();
+        assert_eq!(res.status(), 201);
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"POST /todo?page=1 hello Some("127.0.0.1")"#
+        );
+
+        upstream.join().unwrap();
+        stop(vec![handle], compiler()).unwrap();
+    }
+
+    #[test]
+    fn hmac_token() {
+        let secret = Primitive::String("secret".to_string());
+        let sign = |exp: i128| {
+            let payload = Primitive::Struct(BTreeMap::from([
+                ("sub".to_string(), Primitive::String("alice".to_string())),
+                ("exp".to_string(), Primitive::Int(exp)),
+            ]));
+            let Ok(Primitive::String(token)) =
+                sign_token(vec![payload, secret.clone()], compiler())
+            else {
+                panic!("could not sign token");
+            };
+            token
+        };
+        let verify = |token: String, secret: &str| {
+            verify_token(
+                vec![
+                    Primitive::String(token),
+                    Primitive::String(secret.to_string()),
+                ],
+                compiler(),
+            )
+            .unwrap()
+        };
+
+        let Primitive::Struct(payload) = verify(sign(i64::MAX as i128), "secret") else {
+            panic!("token should be valid");
+        };
+        assert!(matches!(payload.get("sub"), Some(Primitive::String(s)) if s == "alice"));
+        assert!(matches!(
+            verify(sign(i64::MAX as i128), "other"),
+            Primitive::Null
+        ));
+        assert!(matches!(verify(sign(0), "secret"), Primitive::Null));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let (server, url) = local_server();
+        let settings = Primitive::Struct(BTreeMap::from([
+            ("store".to_string(), Primitive::Struct(BTreeMap::new())),
+            ("routes".to_string(), Primitive::Array(vec![route("/")])),
+            (
+                "rate_limit".to_string(),
+                Primitive::Struct(BTreeMap::from([
+                    ("requests".to_string(), Primitive::Int(2)),
+                    ("per".to_string(), Primitive::Int(60000)),
+                ])),
+            ),
+        ]));
+        let handle = start(vec![server, settings], compiler()).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(ureq::get(&format!("{url}/")).call().unwrap().status(), 200);
+        }
+        let Err(ureq::Error::Status(429, res)) = ureq::get(&format!("{url}/")).call() else {
+            panic!("third request should be rate limited");
+        };
+        assert_eq!(res.header("Retry-After"), Some("30"));
+
+        stop(vec![handle], compiler()).unwrap();
+    }
+
+    #[test]
+    fn rate_limit_virtual_hosts() {
+        let (server, url) = local_server();
+        let settings = Primitive::Struct(BTreeMap::from([
+            ("store".to_string(), Primitive::Struct(BTreeMap::new())),
+            ("routes".to_string(), Primitive::Array(vec![route("/")])),
+            (
+                "hosts".to_string(),
+                Primitive::Struct(BTreeMap::from([(
+                    "a.local".to_string(),
+                    Primitive::Struct(BTreeMap::from([(
+                        "routes".to_string(),
+                        Primitive::Array(vec![route("/a")]),
+                    )])),
+                )])),
+            ),
+            (
+                "rate_limit".to_string(),
+                Primitive::Struct(BTreeMap::from([
+                    ("requests".to_string(), Primitive::Int(2)),
+                    ("per".to_string(), Primitive::Int(60000)),
+                ])),
+            ),
+        ]));
+        let handle = start(vec![server, settings], compiler()).unwrap();
+
+        let status = |host: &str, path: &str| match ureq::get(&format!("{url}{path}"))
+            .set("Host", host)
+            .call()
+        {
+            Ok(res) => res.status(),
+            Err(ureq::Error::Status(status, _)) => status,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(status("a.local", "/a"), 200);
+        assert_eq!(status("c.local", "/"), 200);
+        assert_eq!(status("a.local", "/a"), 429);
+
+        stop(vec![handle], compiler()).unwrap();
+    }
+
+    #[test]
+    fn openapi_document() {
+        let Primitive::Struct(mut hello) = route("/hello/:name") else {
+            unreachable!()
+        };
+        hello.insert(
+            "summary".to_string(),
+            Primitive::String("say hello".to_string()),
+        );
+        hello.insert(
+            "query".to_string(),
+            Primitive::Struct(BTreeMap::from([
+                (
+                    "properties".to_string(),
+                    Primitive::Struct(BTreeMap::from([(
+                        "lang".to_string(),
+                        Primitive::Struct(BTreeMap::from([(
+                            "type".to_string(),
+                            Primitive::String("string".to_string()),
+                        )])),
+                    )])),
+                ),
+                (
+                    "required".to_string(),
+                    Primitive::Array(vec![Primitive::String("lang".to_string())]),
+                ),
+            ])),
+        );
+        let settings = Primitive::Struct(BTreeMap::from([(
+            "routes".to_string(),
+            Primitive::Array(vec![Primitive::Struct(hello)]),
+        )]));
+        let info = Primitive::Struct(BTreeMap::from([(
+            "title".to_string(),
+            Primitive::String("hello api".to_string()),
+        )]));
+        let Ok(Primitive::String(doc)) = openapi(vec![settings, info], compiler()) else {
+            panic!("could not generate openapi document");
+        };
+        let Ok(Primitive::Struct(doc)) = Primitive::from_json(&doc) else {
+            panic!("invalid json");
+        };
+        assert!(matches!(doc.get("openapi"), Some(Primitive::String(v)) if v == "3.0.3"));
+        let Some(Primitive::Struct(paths)) = doc.get("paths") else {
+            panic!("missing paths");
+        };
+        let Some(Primitive::Struct(hello)) = paths.get("/hello/{name}") else {
+            panic!("missing /hello/{{name}}");
+        };
+        let Some(Primitive::Struct(get)) = hello.get("get") else {
+            panic!("missing get operation");
+        };
+        assert!(matches!(get.get("summary"), Some(Primitive::String(s)) if s == "say hello"));
+        assert!(matches!(get.get("parameters"), Some(Primitive::Array(p)) if p.len() == 2));
+    }
+
+    #[test]
+    fn request_validation() {
+        let schema = |fields: &[(&str, Primitive)]| {
+            Primitive::Struct(
+                fields
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect(),
+            )
+        };
+        let string = |s: &str| Primitive::String(s.to_string());
+        let Primitive::Struct(mut todo) = route("/todo") else {
+            unreachable!()
+        };
+        todo.insert("method".to_string(), string("POST"));
+        todo.insert(
+            "body_schema".to_string(),
+            schema(&[
+                ("type", string("object")),
+                ("required", Primitive::Array(vec![string("title")])),
+                (
+                    "properties",
+                    schema(&[
+                        (
+                            "title",
+                            schema(&[("type", string("string")), ("minLength", Primitive::Int(1))]),
+                        ),
+                        (
+                            "priority",
+                            schema(&[(
+                                "enum",
+                                Primitive::Array(vec![string("low"), string("high")]),
+                            )]),
+                        ),
+                    ]),
+                ),
+            ]),
+        );
+        todo.insert(
+            "query_schema".to_string(),
+            schema(&[(
+                "properties",
+                schema(&[(
+                    "page",
+                    schema(&[("type", string("integer")), ("minimum", Primitive::Int(1))]),
+                )]),
+            )]),
+        );
+        // same schema, behind an auth that rejects everyone
+        let mut private = todo.clone();
+        private.insert("path".to_string(), string("/private"));
+        private.insert(
+            "auth".to_string(),
+            schema(&[(
+                "bearer",
+                Primitive::Function {
+                    parameters: vec![Value::Variable("token".to_string())],
+                    exprs: vec![],
+                },
+            )]),
+        );
+        let (server, url) = local_server();
+        let settings = schema(&[
+            ("store", Primitive::Struct(BTreeMap::new())),
+            (
+                "routes",
+                Primitive::Array(vec![Primitive::Struct(todo), Primitive::Struct(private)]),
+            ),
+        ]);
+        let handle = start(vec![server, settings], compiler()).unwrap();
+
+        let post = |query: &str, body: &str| {
+            ureq::post(&format!("{url}/todo{query}"))
+                .set("Content-Type", "application/json")
+                .send_string(body)
+        };
+        assert_eq!(
+            post("?page=2", r#"{"title": "hello"}"#).unwrap().status(),
+            200
+        );
+        let Err(ureq::Error::Status(422, res)) = post("?page=0", r#"{"priority": "urgent"}"#)
+        else {
+            panic!("request should be invalid");
+        };
+        let Ok(Primitive::Array(violations)) = Primitive::from_json(&res.into_string().unwrap())
+        else {
+            panic!("violations should be a json array");
+        };
+        let paths = violations
+            .iter()
+            .filter_map(|v| match v {
+                Primitive::Struct(v) => v.get("path").map(|p| p.to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["query.page", "body.title", "body.priority"]);
+
+        let invalid = ureq::post(&format!("{url}/private"))
+            .set("Content-Type", "application/json")
+            .send_string(r#"{"priority": "urgent"}"#);
+        assert!(matches!(invalid, Err(ureq::Error::Status(401, _))));
+
+        stop(vec![handle], compiler()).unwrap();
+    }
+
+    #[test]
+    fn store_file() {
+        let path = std::env::temp_dir().join(format!("adana-store-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"todos": ["hello"]}"#).unwrap();
+
+        let store = Primitive::Struct(BTreeMap::new()).ref_prim();
+        let (server, _) = local_server();
+        let settings = Primitive::Struct(BTreeMap::from([
+            ("store".to_string(), Primitive::Ref(store.clone())),
+            ("routes".to_string(), Primitive::Array(vec![])),
+            (
+                "store_file".to_string(),
+                Primitive::String(path.display().to_string()),
+            ),
+        ]));
+        let handle = start(vec![server, settings], compiler()).unwrap();
+        {
+            let mut store = store.write().unwrap();
+            let Primitive::Struct(store) = &mut *store else {
+                panic!("store should have been loaded from the file");
+            };
+            assert_eq!(
+                store.get("todos").map(|t| t.to_string()),
+                Some(r#"["hello"]"#.to_string())
+            );
+            store.insert("count".to_string(), Primitive::Int(1));
+        }
+        stop(vec![handle], compiler()).unwrap();
+
+        let Ok(Primitive::Struct(saved)) =
+            Primitive::from_json(&std::fs::read_to_string(&path).unwrap())
+        else {
+            panic!("store file should contain a json object");
+        };
+        assert!(matches!(saved.get("count"), Some(Primitive::Int(1))));
+        assert!(matches!(saved.get("todos"), Some(Primitive::Array(t)) if t.len() == 1));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn server_metrics() {
+        let (server, url) = local_server();
+        let settings = Primitive::Struct(BTreeMap::from([
+            ("store".to_string(), Primitive::Struct(BTreeMap::new())),
+            (
+                "routes".to_string(),
+                Primitive::Array(vec![route("/todo/:id")]),
+            ),
+            (
+                "metrics_path".to_string(),
+                Primitive::String("/metrics".to_string()),
+            ),
+        ]));
+        let handle = start(vec![server, settings], compiler()).unwrap();
+
+        for id in 0..2 {
+            ureq::get(&format!("{url}/todo/{id}")).call().unwrap();
+        }
+        let Err(ureq::Error::Status(404, _)) = ureq::get(&format!("{url}/nope")).call() else {
+            panic!("route should not exist");
+        };
+
+        // the server loop records a request after responding to it, served sequentially
+        let text = ureq::get(&format!("{url}/metrics"))
+            .call()
+            .unwrap()
+            .into_string()
+            .unwrap();
+        assert!(text.contains(r#"adana_http_requests_total{route="GET /todo/:id"} 2"#));
+        assert!(text.contains(r#"adana_http_responses_total{status="404"} 1"#));
+        assert!(text.contains(r#"adana_http_request_duration_seconds_count{route="not_found"} 1"#));
+
+        let Primitive::Struct(metrics) = metrics(vec![handle.clone()], compiler()).unwrap() else {
+            panic!("metrics should be a struct");
+        };
+        let Some(Primitive::Struct(routes)) = metrics.get("routes") else {
+            panic!("missing routes");
+        };
+        let Some(Primitive::Struct(todo)) = routes.get("GET /todo/:id") else {
+            panic!("missing route metrics");
+        };
+        assert!(matches!(todo.get("count"), Some(Primitive::Int(2))));
+        assert!(matches!(todo.get("p99"), Some(Primitive::Double(_))));
+        let Some(Primitive::Struct(statuses)) = metrics.get("statuses") else {
+            panic!("missing statuses");
+        };
+        // the scrape of /metrics may be counted too
+        assert!(matches!(statuses.get("200"), Some(Primitive::Int(n)) if *n >= 2));
+        assert!(matches!(statuses.get("404"), Some(Primitive::Int(1))));
+
+        stop(vec![handle], compiler()).unwrap();
+    }
+
+    #[test]
+    fn virtual_hosts() {
+        let host = |path: &str| {
+            Primitive::Struct(BTreeMap::from([(
+                "routes".to_string(),
+                Primitive::Array(vec![route(path)]),
+            )]))
+        };
+        let (server, url) = local_server();
+        let settings = Primitive::Struct(BTreeMap::from([
+            ("store".to_string(), Primitive::Struct(BTreeMap::new())),
+            ("routes".to_string(), Primitive::Array(vec![route("/")])),
+            (
+                "hosts".to_string(),
+                Primitive::Struct(BTreeMap::from([
+                    ("a.local".to_string(), host("/a")),
+                    ("*.b.local".to_string(), host("/b")),
+                ])),
+            ),
+        ]));
+        let handle = start(vec![server, settings], compiler()).unwrap();
+
+        let status = |host: &str, path: &str| match ureq::get(&format!("{url}{path}"))
+            .set("Host", host)
+            .call()
+        {
+            Ok(res) => res.status(),
+            Err(ureq::Error::Status(status, _)) => status,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(status("a.local", "/a"), 200);
+        assert_eq!(status("A.LOCAL:8000", "/a"), 200);
+        assert_eq!(status("a.local", "/"), 404);
+        assert_eq!(status("api.b.local", "/b"), 200);
+        assert_eq!(status("b.local", "/b"), 404);
+        assert_eq!(status("c.local", "/"), 200);
+        assert_eq!(status("c.local", "/a"), 404);
+
+        stop(vec![handle], compiler()).unwrap();
+    }
+
+    #[test]
+    fn template_response() {
+        let compiler = || -> Box<Compiler> {
+            Box::new(|_, _| {
+                Ok(Primitive::Struct(BTreeMap::from([
+                    ("status".to_string(), Primitive::Int(200)),
+                    (
+                        "template".to_string(),
+                        Primitive::String("<p>{{ name }}</p>".to_string()),
+                    ),
+                    (
+                        "ctx".to_string(),
+                        Primitive::Struct(BTreeMap::from([(
+                            "name".to_string(),
+                            Primitive::String("<adana>".to_string()),
+                        )])),
+                    ),
+                ])))
+            })
+        };
+        let (server, url) = local_server();
+        let settings = Primitive::Struct(BTreeMap::from([
+            ("store".to_string(), Primitive::Struct(BTreeMap::new())),
+            ("routes".to_string(), Primitive::Array(vec![route("/")])),
+        ]));
+        let handle = start(vec![server, settings], compiler()).unwrap();
+
+        let res = ureq::get(&format!("{url}/")).call().unwrap();
+        assert_eq!(res.header("Content-Type"), Some("text/html"));
+        assert_eq!(res.into_string().unwrap(), "<p>&lt;adana&gt;</p>");
+
+        stop(vec![handle], compiler()).unwrap();
+    }
+
+    fn slow_compiler(delay: u64) -> Box<Compiler> {
+        Box::new(move |_, _| {
+            std::thread::sleep(std::time::Duration::from_millis(delay));
+            Ok(Primitive::Unit)
+        })
+    }
+
+    fn start_server(compiler: Box<Compiler>) -> (Primitive, String) {
+        let (server, url) = local_server();
+        let settings = Primitive::Struct(BTreeMap::from([
+            ("store".to_string(), Primitive::Struct(BTreeMap::new())),
+            ("routes".to_string(), Primitive::Array(vec![route("/")])),
+        ]));
+        (start(vec![server, settings], compiler).unwrap(), url)
+    }
+
+    /// Waits until the server thread picked up a request.
+    fn wait_in_flight(handle: &Primitive) {
+        for _ in 0..100 {
+            let Primitive::Struct(status) = status(vec![handle.clone()], compiler()).unwrap()
+            else {
+                panic!("status should be a struct");
+            };
+            if matches!(status.get("in_flight"), Some(Primitive::Int(1))) {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        panic!("request was not picked up by the server");
+    }
+
+    #[test]
+    fn stop_clean() {
+        let (handle, url) = start_server(compiler());
+        assert_eq!(ureq::get(&format!("{url}/")).call().unwrap().status(), 200);
+        stop(vec![handle.clone(), Primitive::Int(0)], compiler()).unwrap();
+
+        let Primitive::Struct(status) = status(vec![handle.clone()], compiler()).unwrap() else {
+            panic!("status should be a struct");
+        };
+        assert!(matches!(
+            status.get("running"),
+            Some(Primitive::Bool(false))
+        ));
+        assert!(matches!(status.get("stopped"), Some(Primitive::Bool(true))));
+        assert!(stop(vec![handle], compiler()).is_err());
+    }
+
+    #[test]
+    fn stop_drains_queued_requests() {
+        let (handle, url) = start_server(slow_compiler(200));
+        let get = || {
+            let url = url.clone();
+            std::thread::spawn(move || ureq::get(&format!("{url}/")).call())
+        };
+        let serving = get();
+        wait_in_flight(&handle);
+        let queued = get();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        stop(vec![handle, Primitive::Int(2000)], compiler()).unwrap();
+        assert_eq!(serving.join().unwrap().unwrap().status(), 200);
+        assert_eq!(queued.join().unwrap().unwrap().status(), 200);
+    }
+
+    #[test]
+    fn stop_timeout() {
+        let (handle, url) = start_server(slow_compiler(600));
+        let serving = std::thread::spawn(move || ureq::get(&format!("{url}/")).call());
+        wait_in_flight(&handle);
+
+        let err = stop(vec![handle.clone(), Primitive::Int(0)], compiler()).unwrap_err();
+        assert_eq!(err.to_string(), "server did not stop within 0ms");
+        // still stopping, it can be waited for again
+        stop(vec![handle], compiler()).unwrap();
+        assert_eq!(serving.join().unwrap().unwrap().status(), 200);
+    }
+
+    fn status_of(url: &str) -> u16 {
+        match ureq::get(url).call() {
+            Ok(res) => res.status(),
+            Err(ureq::Error::Status(status, _)) => status,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn update_routes() {
+        let (server, url) = local_server();
+        let settings = |path: &str| {
+            BTreeMap::from([("routes".to_string(), Primitive::Array(vec![route(path)]))])
+        };
+        let mut initial = settings("/a");
+        initial.insert("store".to_string(), Primitive::Struct(BTreeMap::new()));
+        let handle = start(vec![server, Primitive::Struct(initial)], compiler()).unwrap();
+        assert_eq!(status_of(&format!("{url}/a")), 200);
+
+        update(
+            vec![handle.clone(), Primitive::Struct(settings("/b"))],
+            compiler(),
+        )
+        .unwrap();
+        // applied at the next poll of the server loop
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        assert_eq!(status_of(&format!("{url}/a")), 404);
+        assert_eq!(status_of(&format!("{url}/b")), 200);
+
+        let mut with_store = settings("/c");
+        with_store.insert("store".to_string(), Primitive::Struct(BTreeMap::new()));
+        let err = update(
+            vec![handle.clone(), Primitive::Struct(with_store)],
+            compiler(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("store can only be set"));
+
+        stop(vec![handle], compiler()).unwrap();
+    }
+
+    #[test]
+    fn watch_route_file() {
+        let path = std::env::temp_dir().join(format!("adana-routes-{}.adana", std::process::id()));
+        std::fs::write(&path, "struct {routes: [/* a */]}").unwrap();
+        // evaluating the route file returns the /b route, handlers return nothing
+        let compiler = || -> Box<Compiler> {
+            Box::new(|value, _| match value {
+                Value::BuiltInFunction {
+                    fn_type: BuiltInFunctionType::Eval,
+                    ..
+                } => Ok(Primitive::Struct(BTreeMap::from([(
+                    "routes".to_string(),
+                    Primitive::Array(vec![route("/b")]),
+                )]))),
+                _ => Ok(Primitive::Unit),
+            })
+        };
+        let (server, url) = local_server();
+        let settings = Primitive::Struct(BTreeMap::from([
+            ("store".to_string(), Primitive::Struct(BTreeMap::new())),
+            ("routes".to_string(), Primitive::Array(vec![route("/a")])),
+            (
+                "watch".to_string(),
+                Primitive::String(path.display().to_string()),
+            ),
+        ]));
+        let handle = start(vec![server, settings], compiler()).unwrap();
+        assert_eq!(status_of(&format!("{url}/a")), 200);
+
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(1))
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        assert_eq!(status_of(&format!("{url}/a")), 404);
+        assert_eq!(status_of(&format!("{url}/b")), 200);
+
+        stop(vec![handle], compiler()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket() {
+        use std::{
+            io::{Read, Write},
+            os::unix::{fs::PermissionsExt, net::UnixStream},
+        };
+
+        let path = std::env::temp_dir().join(format!("adana-http-{}.sock", std::process::id()));
+        let addr = Primitive::String(format!("unix:{}", path.display()));
+        let mode =
+            |mode: Primitive| Primitive::Struct(BTreeMap::from([("mode".to_string(), mode)]));
+
+        // left behind by a previous run, nothing listens on it anymore
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let server = new(vec![addr.clone(), mode(Primitive::Int(660))], compiler()).unwrap();
+        let permissions = std::fs::metadata(&path).unwrap().permissions();
+        assert_eq!(permissions.mode() & 0o777, 0o660);
+
+        let settings = Primitive::Struct(BTreeMap::from([
+            ("store".to_string(), Primitive::Struct(BTreeMap::new())),
+            ("routes".to_string(), Primitive::Array(vec![route("/")])),
+        ]));
+        let handle = start(vec![server, settings], compiler()).unwrap();
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"), "{res}");
+
+        // the socket of a live server is not taken away
+        let err = new(vec![addr.clone()], compiler()).unwrap_err();
+        assert!(err.to_string().contains("used by a running server"));
+        assert!(new(vec![addr, mode(Primitive::Int(999))], compiler()).is_err());
+
+        stop(vec![handle], compiler()).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn route_auth() {
+        let function = |params: &[&str]| Primitive::Function {
+            parameters: params
+                .iter()
+                .map(|p| Value::Variable(p.to_string()))
+                .collect(),
+            exprs: vec![],
+        };
+        // basic accepts admin:secret, bearer returns a user for "good-token",
+        // and the handler responds with the name of req.user
+        let compiler = || -> Box<Compiler> {
+            Box::new(|value, _| {
+                let Value::FunctionCall { parameters, .. } = value else {
+                    return Ok(Primitive::Unit);
+                };
+                let Value::BlockParen(parameters) = *parameters else {
+                    return Ok(Primitive::Unit);
+                };
+                let params = parameters
+                    .into_iter()
+                    .map(|p| match p {
+                        Value::Primitive(p) => p,
+                        p => panic!("unexpected parameter {p:?}"),
+                    })
+                    .collect::<Vec<_>>();
+                match &params[..] {
+                    [Primitive::String(user), Primitive::String(pass)] => {
+                        Ok(Primitive::Bool(user == "admin" && pass == "secret"))
+                    }
+                    [Primitive::String(token)] if token == "good-token" => {
+                        Ok(Primitive::Struct(BTreeMap::from([(
+                            "name".to_string(),
+                            Primitive::String("alice".to_string()),
+                        )])))
+                    }
+                    [Primitive::String(_)] => Ok(Primitive::Null),
+                    [Primitive::Ref(req), _] => {
+                        let Primitive::Struct(req) = &*req.read().unwrap() else {
+                            panic!("req should be a struct");
+                        };
+                        Ok(match req.get("user") {
+                            Some(Primitive::Struct(user)) => user["name"].clone(),
+                            Some(user) => user.clone(),
+                            None => Primitive::Null,
+                        })
+                    }
+                    _ => Ok(Primitive::Unit),
+                }
+            })
+        };
+        let Primitive::Struct(mut protected) = route("/private") else {
+            unreachable!()
+        };
+        protected.insert(
+            "auth".to_string(),
+            Primitive::Struct(BTreeMap::from([
+                ("basic".to_string(), function(&["user", "pass"])),
+                ("bearer".to_string(), function(&["token"])),
+            ])),
+        );
+        let (server, url) = local_server();
+        let settings = Primitive::Struct(BTreeMap::from([
+            ("store".to_string(), Primitive::Struct(BTreeMap::new())),
+            (
+                "routes".to_string(),
+                Primitive::Array(vec![Primitive::Struct(protected)]),
+            ),
+        ]));
+        let handle = start(vec![server, settings], compiler()).unwrap();
+
+        let call = |authorization: Option<&str>| {
+            let req = ureq::get(&format!("{url}/private"));
+            let req = match authorization {
+                Some(authorization) => req.set("Authorization", authorization),
+                None => req,
+            };
+            match req.call() {
+                Ok(res) => res,
+                Err(ureq::Error::Status(_, res)) => res,
+                Err(e) => panic!("{e}"),
+            }
+        };
+        let res = call(None);
+        assert_eq!(res.status(), 401);
+        assert_eq!(
+            res.all("WWW-Authenticate"),
+            [r#"Basic realm="adana""#, r#"Bearer realm="adana""#]
+        );
+        // admin:wrong and admin:secret
+        assert_eq!(call(Some("Basic YWRtaW46d3Jvbmc=")).status(), 401);
+        assert_eq!(call(Some("Bearer bad-token")).status(), 401);
+        assert_eq!(call(Some("Digest abc")).status(), 401);
+
+        let res = call(Some("Basic YWRtaW46c2VjcmV0"));
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.into_string().unwrap(), "admin");
+        let res = call(Some("Bearer good-token"));
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.into_string().unwrap(), "alice");
+
+        // the body is only parsed once the client is authenticated
+        let invalid_json = |authorization: &str| match ureq::get(&format!("{url}/private"))
+            .set("Authorization", authorization)
+            .set("Content-Type", "application/json")
+            .send_string("{")
+        {
+            Ok(res) => res,
+            Err(ureq::Error::Status(_, res)) => res,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(invalid_json("Bearer bad-token").status(), 401);
+        let res = invalid_json("Bearer good-token");
+        assert_eq!(res.status(), 400);
+        let body = res.into_string().unwrap();
+        assert!(body.contains(r#""error": "invalid body: EOF while parsing"#));
+
+        stop(vec![handle], compiler()).unwrap();
     }
 }
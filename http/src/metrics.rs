@@ -0,0 +1,139 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    time::Duration,
+};
+
+use adana_script_core::primitive::Primitive;
+
+/// Latencies kept per route to compute the percentiles.
+const LATENCY_WINDOW: usize = 1024;
+const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+#[derive(Debug, Default)]
+struct RouteMetrics {
+    count: u64,
+    total: Duration,
+    latencies: VecDeque<Duration>,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    routes: BTreeMap<String, RouteMetrics>,
+    statuses: BTreeMap<u16, u64>,
+}
+
+impl RouteMetrics {
+    fn quantile(&self, q: f64) -> Duration {
+        let mut latencies = self.latencies.iter().copied().collect::<Vec<_>>();
+        latencies.sort();
+        let idx = ((latencies.len() as f64 * q).ceil() as usize).saturating_sub(1);
+        latencies.get(idx).copied().unwrap_or_default()
+    }
+}
+
+fn millis(d: Duration) -> Primitive {
+    Primitive::Double(d.as_secs_f64() * 1000.)
+}
+
+impl Metrics {
+    pub fn record(&mut self, route: String, status: u16, latency: Duration) {
+        *self.statuses.entry(status).or_default() += 1;
+        let route = self.routes.entry(route).or_default();
+        route.count += 1;
+        route.total += latency;
+        if route.latencies.len() == LATENCY_WINDOW {
+            route.latencies.pop_front();
+        }
+        route.latencies.push_back(latency);
+    }
+
+    /// Count and latencies (in ms) per route, count per status code.
+    pub fn to_primitive(&self) -> (Primitive, Primitive) {
+        let routes = self
+            .routes
+            .iter()
+            .map(|(name, route)| {
+                let mut metrics = BTreeMap::from([
+                    ("count".to_string(), Primitive::Int(route.count as i128)),
+                    (
+                        "mean".to_string(),
+                        // a route is only recorded with its first request, count is never 0
+                        Primitive::Double(route.total.as_secs_f64() * 1000. / route.count as f64),
+                    ),
+                ]);
+                for q in QUANTILES {
+                    metrics.insert(format!("p{}", (q * 100.) as u8), millis(route.quantile(q)));
+                }
+                (name.to_string(), Primitive::Struct(metrics))
+            })
+            .collect();
+        let statuses = self
+            .statuses
+            .iter()
+            .map(|(status, count)| (status.to_string(), Primitive::Int(*count as i128)))
+            .collect();
+        (Primitive::Struct(routes), Primitive::Struct(statuses))
+    }
+
+    /// Prometheus text exposition format.
+    pub fn to_prometheus(&self, in_flight: u64, errors: u64) -> String {
+        let mut out = String::new();
+        let label = |route: &str| route.replace('\\', r"\\").replace('"', r#"\""#);
+
+        let _ = writeln!(
+            out,
+            "# HELP adana_http_requests_total Requests served per route.\n# TYPE adana_http_requests_total counter"
+        );
+        for (name, route) in &self.routes {
+            let _ = writeln!(
+                out,
+                r#"adana_http_requests_total{{route="{}"}} {}"#,
+                label(name),
+                route.count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP adana_http_responses_total Responses per status code.\n# TYPE adana_http_responses_total counter"
+        );
+        for (status, count) in &self.statuses {
+            let _ = writeln!(
+                out,
+                r#"adana_http_responses_total{{status="{status}"}} {count}"#
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP adana_http_request_duration_seconds Request latency per route.\n# TYPE adana_http_request_duration_seconds summary"
+        );
+        for (name, route) in &self.routes {
+            let name = label(name);
+            for q in QUANTILES {
+                let _ = writeln!(
+                    out,
+                    r#"adana_http_request_duration_seconds{{route="{name}",quantile="{q}"}} {}"#,
+                    route.quantile(q).as_secs_f64()
+                );
+            }
+            let _ = writeln!(
+                out,
+                "adana_http_request_duration_seconds_sum{{route=\"{name}\"}} {}\nadana_http_request_duration_seconds_count{{route=\"{name}\"}} {}",
+                route.total.as_secs_f64(),
+                route.count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP adana_http_in_flight_requests Requests being served.\n# TYPE adana_http_in_flight_requests gauge\nadana_http_in_flight_requests {in_flight}"
+        );
+        let _ = writeln!(
+            out,
+            "# HELP adana_http_errors_total Requests that failed before a response was sent.\n# TYPE adana_http_errors_total counter\nadana_http_errors_total {errors}"
+        );
+        out
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+
+use adana_script_core::primitive::{Json, Primitive};
+use tiny_http::{Request, Response};
+
+use crate::{
+    APPLICATION_JSON, CONTENT_TYPE, PathSegment, Route, TEXT_HTML, make_header, respond,
+    server_header,
+};
+
+pub const DEFAULT_TITLE: &str = "Adana API";
+pub const DEFAULT_VERSION: &str = "1.0.0";
+const OPENAPI_JSON: &str = "/openapi.json";
+
+/// Optional documentation of a route, e.g `summary: "list todos"`,
+/// `query: struct {type: "object", properties: struct {page: struct {type: "integer"}}}`
+/// and `body: struct {type: "object", ...}`
+#[derive(Debug, Default)]
+pub struct RouteDoc {
+    pub summary: Option<String>,
+    pub query: Option<Primitive>,
+    pub body: Option<Primitive>,
+}
+
+#[derive(Debug)]
+pub struct Docs {
+    path: String,
+    document: String,
+}
+
+fn string(s: impl Into<String>) -> Primitive {
+    Primitive::String(s.into())
+}
+
+fn object<const N: usize>(fields: [(&str, Primitive); N]) -> Primitive {
+    Primitive::Struct(
+        fields
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    )
+}
+
+fn openapi_path(route: &Route) -> String {
+    let mut path = String::new();
+    for segment in &route.path_segments {
+        match segment {
+            PathSegment::Root => {}
+            PathSegment::String(s) => {
+                path.push('/');
+                path.push_str(s);
+            }
+            PathSegment::Variable { name, .. } => {
+                path.push_str(&format!("/{{{name}}}"));
+            }
+        }
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+fn parameter(name: &str, location: &str, required: bool, schema: Primitive) -> Primitive {
+    object([
+        ("name", string(name)),
+        ("in", string(location)),
+        ("required", Primitive::Bool(required)),
+        ("schema", schema),
+    ])
+}
+
+fn operation(route: &Route) -> Primitive {
+    let mut parameters = vec![];
+    for segment in &route.path_segments {
+        if let PathSegment::Variable { name, .. } = segment {
+            parameters.push(parameter(
+                name,
+                "path",
+                true,
+                object([("type", string("string"))]),
+            ));
+        }
+    }
+    if let Some(Primitive::Struct(query)) = &route.doc.query {
+        let required = match query.get("required") {
+            Some(Primitive::Array(required)) => required.iter().map(|r| r.to_string()).collect(),
+            _ => vec![],
+        };
+        if let Some(Primitive::Struct(properties)) = query.get("properties") {
+            for (name, schema) in properties {
+                parameters.push(parameter(
+                    name,
+                    "query",
+                    required.contains(name),
+                    schema.clone(),
+                ));
+            }
+        }
+    }
+
+    let mut operation = BTreeMap::from([
+        ("parameters".to_string(), Primitive::Array(parameters)),
+        (
+            "responses".to_string(),
+            object([("200", object([("description", string("OK"))]))]),
+        ),
+    ]);
+    if let Some(summary) = &route.doc.summary {
+        operation.insert("summary".to_string(), string(summary));
+    }
+    if let Some(body) = &route.doc.body {
+        operation.insert(
+            "requestBody".to_string(),
+            object([(
+                "content",
+                object([(APPLICATION_JSON, object([("schema", body.clone())]))]),
+            )]),
+        );
+    }
+    Primitive::Struct(operation)
+}
+
+/// OpenAPI 3 document describing the routes.
+pub fn document(routes: &[Route], title: &str, version: &str) -> Primitive {
+    let mut paths: BTreeMap<String, Primitive> = BTreeMap::new();
+    for route in routes {
+        let path = paths
+            .entry(openapi_path(route))
+            .or_insert_with(|| Primitive::Struct(BTreeMap::new()));
+        if let Primitive::Struct(path) = path {
+            path.insert(route.method.as_str().to_lowercase(), operation(route));
+        }
+    }
+    object([
+        ("openapi", string("3.0.3")),
+        (
+            "info",
+            object([("title", string(title)), ("version", string(version))]),
+        ),
+        ("paths", Primitive::Struct(paths)),
+    ])
+}
+
+impl Docs {
+    pub fn new(path: String, routes: &[Route]) -> anyhow::Result<Docs> {
+        let document = document(routes, DEFAULT_TITLE, DEFAULT_VERSION).to_json()?;
+        Ok(Docs {
+            path: path.trim_end_matches('/').to_string(),
+            document,
+        })
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let path = path.trim_end_matches('/');
+        path == self.path || path.strip_prefix(&self.path) == Some(OPENAPI_JSON)
+    }
+
+    /// Serves the OpenAPI document, or the html viewer fetching it.
+    pub fn serve(&self, request: Request, path: &str) -> anyhow::Result<u16> {
+        let response = if path.trim_end_matches('/').ends_with(OPENAPI_JSON) {
+            Response::from_string(self.document.as_str())
+                .with_header(make_header(CONTENT_TYPE, APPLICATION_JSON)?)
+        } else {
+            Response::from_string(DOCS_HTML.replace("{{openapi_url}}", &self.path))
+                .with_header(make_header(CONTENT_TYPE, TEXT_HTML)?)
+        };
+        respond(request, response.with_header(server_header()))
+    }
+}
+
+const DOCS_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>API documentation</title>
+  <style>
+    body { font-family: sans-serif; margin: 2em; }
+    .op { border: 1px solid #ddd; border-radius: 4px; margin: 0.5em 0; padding: 0.5em; }
+    .method { font-weight: bold; text-transform: uppercase; display: inline-block; width: 5em; }
+    pre { background: #f6f6f6; padding: 0.5em; }
+  </style>
+</head>
+<body>
+  <h1 id="title"></h1>
+  <div id="paths"></div>
+  <script>
+    fetch("{{openapi_url}}/openapi.json").then(r => r.json()).then(doc => {
+      document.getElementById("title").textContent = doc.info.title + " " + doc.info.version;
+      const root = document.getElementById("paths");
+      for (const [path, operations] of Object.entries(doc.paths)) {
+        for (const [method, op] of Object.entries(operations)) {
+          const el = document.createElement("details");
+          el.className = "op";
+          const summary = document.createElement("summary");
+          summary.innerHTML = `<span class="method"></span><code></code> <span></span>`;
+          summary.children[0].textContent = method;
+          summary.children[1].textContent = path;
+          summary.children[2].textContent = op.summary || "";
+          const details = document.createElement("pre");
+          details.textContent = JSON.stringify({ parameters: op.parameters, requestBody: op.requestBody }, null, 2);
+          el.append(summary, details);
+          root.append(el);
+        }
+      }
+    });
+  </script>
+</body>
+</html>
+"#;
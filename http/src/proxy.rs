@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+use adana_script_core::primitive::Primitive;
+use anyhow::anyhow;
+use tiny_http::{Request, Response, StatusCode};
+
+use crate::{make_header, respond, server_header, to_duration};
+
+const DEFAULT_PROXY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers that only make sense for a single connection, and must not be forwarded.
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+#[derive(Debug)]
+pub struct ProxyPass {
+    pub path: String,
+    upstream: String,
+    agent: ureq::Agent,
+}
+
+fn is_hop_by_hop(header: &str) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(header))
+}
+
+pub fn compile_proxies(proxies: Vec<Primitive>) -> anyhow::Result<Vec<ProxyPass>> {
+    let mut proxy_passes = Vec::with_capacity(proxies.len());
+    for proxy in proxies {
+        let Primitive::Struct(mut proxy) = proxy else {
+            return Err(anyhow!("bad proxy {proxy}"));
+        };
+        let Some(Primitive::String(path)) = proxy.remove("path") else {
+            return Err(anyhow!("missing path in proxy"));
+        };
+        let Some(Primitive::String(upstream)) = proxy.remove("proxy") else {
+            return Err(anyhow!("missing proxy url in proxy {path}"));
+        };
+        if !upstream.starts_with("http://") {
+            return Err(anyhow!("proxy url must start with http:// => {upstream}"));
+        }
+        let timeout = if let Some(timeout) = proxy.remove("timeout") {
+            to_duration(&timeout)?
+        } else {
+            DEFAULT_PROXY_TIMEOUT
+        };
+        let agent = ureq::AgentBuilder::new()
+            .timeout(timeout)
+            .redirects(0)
+            .build();
+        proxy_passes.push(ProxyPass {
+            path: path.trim_end_matches('/').to_string(),
+            upstream: upstream.trim_end_matches('/').to_string(),
+            agent,
+        });
+    }
+    Ok(proxy_passes)
+}
+
+impl ProxyPass {
+    /// Whole segments only, `/api` matches `/api` and `/api/users` but not `/apiary`.
+    pub fn matches(&self, path: &str) -> bool {
+        path.strip_prefix(&self.path)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || self.path.is_empty())
+    }
+
+    /// Path and query of the upstream request, without the proxy path.
+    fn upstream_path<'a>(&self, url: &'a str) -> std::borrow::Cow<'a, str> {
+        let rest = url.strip_prefix(&self.path).unwrap_or(url);
+        if rest.starts_with('/') {
+            rest.into()
+        } else {
+            format!("/{rest}").into()
+        }
+    }
+}
+
+/// Forwards the request to the upstream, replacing the proxy path with the upstream url
+/// (e.g `/api/users?page=1` proxied from `/api` to `http://127.0.0.1:9000` becomes
+/// `http://127.0.0.1:9000/users?page=1`), then streams the upstream response back.
+pub fn forward(mut request: Request, proxy: &ProxyPass) -> anyhow::Result<u16> {
+    let url = format!("{}{}", proxy.upstream, proxy.upstream_path(request.url()));
+    let mut upstream_req = proxy.agent.request(request.method().as_str(), &url);
+
+    let mut forwarded_for = None;
+    let mut original_host = None;
+    for header in request.headers() {
+        let field = header.field.as_str().as_str();
+        if field.eq_ignore_ascii_case("Host") {
+            // the upstream host is set from the url
+            original_host = Some(header.value.to_string());
+        } else if field.eq_ignore_ascii_case("X-Forwarded-For") {
+            forwarded_for = Some(header.value.to_string());
+        } else if !is_hop_by_hop(field) {
+            upstream_req = upstream_req.set(field, header.value.as_str());
+        }
+    }
+    if let Some(remote_addr) = request.remote_addr() {
+        let client = remote_addr.ip().to_string();
+        let forwarded_for = match forwarded_for {
+            Some(forwarded_for) => format!("{forwarded_for}, {client}"),
+            None => client,
+        };
+        upstream_req = upstream_req.set("X-Forwarded-For", &forwarded_for);
+    }
+    if let Some(host) = original_host {
+        upstream_req = upstream_req.set("X-Forwarded-Host", &host);
+    }
+
+    let has_body = request.body_length().is_some_and(|len| len > 0)
+        || request
+            .headers()
+            .iter()
+            .any(|h| h.field.equiv("Transfer-Encoding"));
+    let upstream_res = if has_body {
+        upstream_req.send(request.as_reader())
+    } else {
+        upstream_req.call()
+    };
+
+    let upstream_res = match upstream_res {
+        Ok(res) | Err(ureq::Error::Status(_, res)) => res,
+        Err(ureq::Error::Transport(e)) => {
+            let timed_out = std::error::Error::source(&e)
+                .and_then(|s| s.downcast_ref::<std::io::Error>())
+                .is_some_and(|e| {
+                    matches!(
+                        e.kind(),
+                        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+                    )
+                });
+            let (status, message) = if timed_out {
+                (504, "GATEWAY TIMEOUT")
+            } else {
+                (502, "BAD GATEWAY")
+            };
+            println!("could not reach upstream {url}: {e}");
+            return respond(
+                request,
+                Response::from_string(message)
+                    .with_status_code(status)
+                    .with_header(server_header()),
+            );
+        }
+    };
+
+    let status = upstream_res.status();
+    let mut headers = Vec::new();
+    let mut content_length = None;
+    for name in upstream_res.headers_names() {
+        if name.eq_ignore_ascii_case("Content-Length") {
+            content_length = upstream_res.header(&name).and_then(|l| l.parse().ok());
+        } else if !is_hop_by_hop(&name) {
+            for value in upstream_res.all(&name) {
+                headers.push(make_header(&name, value)?);
+            }
+        }
+    }
+    let response = Response::new(
+        StatusCode(status),
+        headers,
+        upstream_res.into_reader(),
+        content_length,
+        None,
+    );
+    respond(request, response)
+}
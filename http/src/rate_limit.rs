@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use adana_script_core::primitive::{Primitive, ToNumber};
+use anyhow::anyhow;
+use tiny_http::{Request, Response};
+
+use crate::{make_header, respond, server_header, to_duration};
+
+const DEFAULT_PERIOD: Duration = Duration::from_secs(1);
+/// Above this amount of clients, buckets that are full again are dropped.
+const MAX_BUCKETS: usize = 4096;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket per client, keyed by remote address or by the value of a header.
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    tokens_per_ms: f64,
+    header: Option<String>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+fn to_positive_number(p: Option<Primitive>, name: &str) -> anyhow::Result<Option<f64>> {
+    match p.map(|p| p.to_int()) {
+        Some(Primitive::Int(n)) if n > 0 => Ok(Some(n as f64)),
+        Some(e) => Err(anyhow!("{name} must be a positive number => {e}")),
+        None => Ok(None),
+    }
+}
+
+pub fn compile_rate_limit(rate_limit: Primitive) -> anyhow::Result<RateLimiter> {
+    let Primitive::Struct(mut rate_limit) = rate_limit else {
+        return Err(anyhow!(
+            "rate_limit must be a struct (e.g struct {{requests: 10, per: 1000}}) => {rate_limit}"
+        ));
+    };
+    let Some(requests) = to_positive_number(rate_limit.remove("requests"), "requests")? else {
+        return Err(anyhow!("missing requests in rate_limit"));
+    };
+    let period = if let Some(per) = rate_limit.remove("per") {
+        to_duration(&per)?
+    } else {
+        DEFAULT_PERIOD
+    };
+    if period.is_zero() {
+        return Err(anyhow!("per must be greater than 0"));
+    }
+    let capacity = to_positive_number(rate_limit.remove("burst"), "burst")?.unwrap_or(requests);
+    let header = match rate_limit.remove("header") {
+        Some(Primitive::String(header)) => Some(header),
+        Some(e) => return Err(anyhow!("header must be a string => {e}")),
+        None => None,
+    };
+    Ok(RateLimiter {
+        capacity,
+        tokens_per_ms: requests / period.as_millis() as f64,
+        header,
+        buckets: Mutex::new(HashMap::new()),
+    })
+}
+
+impl RateLimiter {
+    fn key(&self, request: &Request) -> String {
+        self.header
+            .as_ref()
+            .and_then(|header| {
+                request
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(header))
+                    .map(|h| h.value.to_string())
+            })
+            .or_else(|| request.remote_addr().map(|addr| addr.ip().to_string()))
+            .unwrap_or_default()
+    }
+
+    /// Takes a token for the client, returns how long it has to wait if there's none left.
+    pub fn acquire(&self, request: &Request) -> Option<Duration> {
+        let key = self.key(request);
+        let now = Instant::now();
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return None;
+        };
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64() * 1000.;
+            (bucket.tokens + elapsed * self.tokens_per_ms).min(self.capacity)
+        };
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|_, bucket| refill(bucket) < self.capacity);
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.capacity,
+            last_refill: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            None
+        } else {
+            let wait_ms = (1. - bucket.tokens) / self.tokens_per_ms;
+            Some(Duration::from_millis(wait_ms.ceil() as u64))
+        }
+    }
+}
+
+pub fn too_many_requests(request: Request, retry_after: Duration) -> anyhow::Result<u16> {
+    let retry_after = retry_after.as_secs_f64().ceil().max(1.) as u64;
+    respond(
+        request,
+        Response::from_string("TOO MANY REQUESTS")
+            .with_status_code(429)
+            .with_header(make_header("Retry-After", &retry_after.to_string())?)
+            .with_header(server_header()),
+    )
+}
@@ -0,0 +1,302 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use adana_script_core::primitive::Primitive;
+use anyhow::anyhow;
+use regex::Regex;
+
+/// Subset of JSON schema used to validate requests:
+/// `type`, `properties`, `required`, `items`, `enum`, `pattern`,
+/// `minimum` / `maximum`, `minLength` / `maxLength` and `minItems` / `maxItems`.
+#[derive(Debug)]
+pub struct Schema {
+    kind: Option<SchemaType>,
+    properties: BTreeMap<String, Schema>,
+    required: Vec<String>,
+    items: Option<Box<Schema>>,
+    enumeration: Option<Vec<Primitive>>,
+    pattern: Option<Regex>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SchemaType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    Array,
+    Object,
+    Null,
+}
+
+/// Returned by `validate_request` when the request doesn't match the route schemas.
+#[derive(Debug)]
+pub struct ValidationError {
+    pub violations: Vec<Primitive>,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid request: {} violation(s)", self.violations.len())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl SchemaType {
+    fn from_name(name: &str) -> anyhow::Result<SchemaType> {
+        match name {
+            "string" => Ok(SchemaType::String),
+            "integer" => Ok(SchemaType::Integer),
+            "number" => Ok(SchemaType::Number),
+            "boolean" => Ok(SchemaType::Boolean),
+            "array" => Ok(SchemaType::Array),
+            "object" => Ok(SchemaType::Object),
+            "null" => Ok(SchemaType::Null),
+            _ => Err(anyhow!("unknown schema type {name}")),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            SchemaType::String => "string",
+            SchemaType::Integer => "integer",
+            SchemaType::Number => "number",
+            SchemaType::Boolean => "boolean",
+            SchemaType::Array => "array",
+            SchemaType::Object => "object",
+            SchemaType::Null => "null",
+        }
+    }
+
+    fn matches(&self, value: &Primitive) -> bool {
+        matches!(
+            (self, value),
+            (SchemaType::String, Primitive::String(_))
+                | (
+                    SchemaType::Integer,
+                    Primitive::Int(_) | Primitive::U8(_) | Primitive::I8(_)
+                )
+                | (
+                    SchemaType::Number,
+                    Primitive::Int(_) | Primitive::U8(_) | Primitive::I8(_) | Primitive::Double(_)
+                )
+                | (SchemaType::Boolean, Primitive::Bool(_))
+                | (SchemaType::Array, Primitive::Array(_))
+                | (SchemaType::Object, Primitive::Struct(_))
+                | (SchemaType::Null, Primitive::Null)
+        )
+    }
+}
+
+fn to_f64(p: &Primitive) -> Option<f64> {
+    match p {
+        Primitive::Int(n) => Some(*n as f64),
+        Primitive::U8(n) => Some(*n as f64),
+        Primitive::I8(n) => Some(*n as f64),
+        Primitive::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+fn to_usize(p: Option<Primitive>, name: &str) -> anyhow::Result<Option<usize>> {
+    match p.as_ref().map(to_f64) {
+        Some(Some(n)) if n >= 0. => Ok(Some(n as usize)),
+        Some(_) => Err(anyhow!("{name} must be a positive number")),
+        None => Ok(None),
+    }
+}
+
+fn to_number(p: Option<Primitive>, name: &str) -> anyhow::Result<Option<f64>> {
+    match p.as_ref().map(to_f64) {
+        Some(Some(n)) => Ok(Some(n)),
+        Some(None) => Err(anyhow!("{name} must be a number")),
+        None => Ok(None),
+    }
+}
+
+pub fn compile_schema(schema: Primitive) -> anyhow::Result<Schema> {
+    let mut schema = match schema {
+        Primitive::Ref(r) => {
+            let r = r
+                .read()
+                .map_err(|e| anyhow!("could not acquire lock {e}"))?;
+            return compile_schema(r.clone());
+        }
+        Primitive::Struct(schema) => schema,
+        e => return Err(anyhow!("schema must be a struct => {e}")),
+    };
+    let kind = match schema.remove("type") {
+        Some(Primitive::String(kind)) => Some(SchemaType::from_name(&kind)?),
+        Some(e) => return Err(anyhow!("schema type must be a string => {e}")),
+        None => None,
+    };
+    let properties = match schema.remove("properties") {
+        Some(Primitive::Struct(properties)) => properties
+            .into_iter()
+            .map(|(k, v)| compile_schema(v).map(|v| (k, v)))
+            .collect::<anyhow::Result<BTreeMap<_, _>>>()?,
+        Some(e) => return Err(anyhow!("schema properties must be a struct => {e}")),
+        None => BTreeMap::new(),
+    };
+    let required = match schema.remove("required") {
+        Some(Primitive::Array(required)) => required.iter().map(|r| r.to_string()).collect(),
+        Some(e) => return Err(anyhow!("schema required must be an array => {e}")),
+        None => vec![],
+    };
+    let enumeration = match schema.remove("enum") {
+        Some(Primitive::Array(values)) => Some(values),
+        Some(e) => return Err(anyhow!("schema enum must be an array => {e}")),
+        None => None,
+    };
+    let pattern = match schema.remove("pattern") {
+        Some(Primitive::String(pattern)) => Some(Regex::new(&pattern)?),
+        Some(e) => return Err(anyhow!("schema pattern must be a string => {e}")),
+        None => None,
+    };
+    Ok(Schema {
+        kind,
+        properties,
+        required,
+        items: schema
+            .remove("items")
+            .map(compile_schema)
+            .transpose()?
+            .map(Box::new),
+        enumeration,
+        pattern,
+        minimum: to_number(schema.remove("minimum"), "minimum")?,
+        maximum: to_number(schema.remove("maximum"), "maximum")?,
+        min_length: to_usize(schema.remove("minLength"), "minLength")?,
+        max_length: to_usize(schema.remove("maxLength"), "maxLength")?,
+        min_items: to_usize(schema.remove("minItems"), "minItems")?,
+        max_items: to_usize(schema.remove("maxItems"), "maxItems")?,
+    })
+}
+
+fn violation(path: &str, message: String) -> Primitive {
+    Primitive::Struct(BTreeMap::from([
+        ("path".to_string(), Primitive::String(path.to_string())),
+        ("message".to_string(), Primitive::String(message)),
+    ]))
+}
+
+impl Schema {
+    /// Query strings, path variables and forms only carry strings,
+    /// convert them to the type declared in the schema of each property.
+    pub fn coerce(&self, value: &mut Primitive) {
+        let Primitive::Struct(fields) = value else {
+            return;
+        };
+        for (name, schema) in &self.properties {
+            let Some(Primitive::String(s)) = fields.get(name) else {
+                continue;
+            };
+            let coerced = match schema.kind {
+                Some(SchemaType::Integer) => s.parse().ok().map(Primitive::Int),
+                Some(SchemaType::Number) => s.parse().ok().map(Primitive::Double),
+                Some(SchemaType::Boolean) => s.parse().ok().map(Primitive::Bool),
+                _ => None,
+            };
+            if let Some(coerced) = coerced {
+                fields.insert(name.to_string(), coerced);
+            }
+        }
+    }
+
+    pub fn validate(&self, value: &Primitive, path: &str, violations: &mut Vec<Primitive>) {
+        if let Primitive::Ref(r) = value {
+            if let Ok(r) = r.read() {
+                self.validate(&r, path, violations);
+            }
+            return;
+        }
+        if let Some(kind) = self.kind {
+            if !kind.matches(value) {
+                violations.push(violation(path, format!("must be of type {}", kind.name())));
+                return;
+            }
+        }
+        if let Some(allowed) = &self.enumeration {
+            if !allowed
+                .iter()
+                .any(|a| matches!(a.is_equal(value), Primitive::Bool(true)))
+            {
+                let allowed = allowed.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+                violations.push(violation(
+                    path,
+                    format!("must be one of {}", allowed.join(", ")),
+                ));
+            }
+        }
+        if let Some(n) = to_f64(value) {
+            if let Some(minimum) = self.minimum.filter(|m| n < *m) {
+                violations.push(violation(path, format!("must be >= {minimum}")));
+            }
+            if let Some(maximum) = self.maximum.filter(|m| n > *m) {
+                violations.push(violation(path, format!("must be <= {maximum}")));
+            }
+        }
+        match value {
+            Primitive::String(s) => {
+                let len = s.chars().count();
+                if let Some(min_length) = self.min_length.filter(|m| len < *m) {
+                    violations.push(violation(
+                        path,
+                        format!("must be at least {min_length} characters long"),
+                    ));
+                }
+                if let Some(max_length) = self.max_length.filter(|m| len > *m) {
+                    violations.push(violation(
+                        path,
+                        format!("must be at most {max_length} characters long"),
+                    ));
+                }
+                if let Some(pattern) = self.pattern.as_ref().filter(|p| !p.is_match(s)) {
+                    violations.push(violation(path, format!("must match {pattern}")));
+                }
+            }
+            Primitive::Array(items) => {
+                if let Some(min_items) = self.min_items.filter(|m| items.len() < *m) {
+                    violations.push(violation(
+                        path,
+                        format!("must have at least {min_items} items"),
+                    ));
+                }
+                if let Some(max_items) = self.max_items.filter(|m| items.len() > *m) {
+                    violations.push(violation(
+                        path,
+                        format!("must have at most {max_items} items"),
+                    ));
+                }
+                if let Some(schema) = &self.items {
+                    for (idx, item) in items.iter().enumerate() {
+                        schema.validate(item, &format!("{path}[{idx}]"), violations);
+                    }
+                }
+            }
+            Primitive::Struct(fields) => {
+                for name in &self.required {
+                    if matches!(fields.get(name), None | Some(Primitive::Null)) {
+                        violations.push(violation(&format!("{path}.{name}"), "is required".into()));
+                    }
+                }
+                for (name, schema) in &self.properties {
+                    if let Some(field) = fields.get(name).filter(|f| !matches!(f, Primitive::Null))
+                    {
+                        schema.validate(field, &format!("{path}.{name}"), violations);
+                    }
+                }
+            }
+            Primitive::Null if !self.required.is_empty() => {
+                violations.push(violation(path, "is required".into()));
+            }
+            _ => {}
+        }
+    }
+}
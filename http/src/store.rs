@@ -0,0 +1,78 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use adana_script_core::primitive::{Json, Primitive};
+use anyhow::anyhow;
+
+pub const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keeps the shared store in sync with a json file on disk.
+pub struct StoreFile {
+    path: PathBuf,
+    interval: Duration,
+    last_snapshot: Instant,
+    last_json: Option<String>,
+}
+
+impl StoreFile {
+    pub fn new(path: PathBuf, interval: Duration) -> StoreFile {
+        StoreFile {
+            path,
+            interval,
+            last_snapshot: Instant::now(),
+            last_json: None,
+        }
+    }
+
+    /// Replaces the content of the store with the file, if it exists.
+    pub fn load(&mut self, store: &Primitive) -> anyhow::Result<()> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+        let json = std::fs::read_to_string(&self.path)?;
+        let loaded @ Primitive::Struct(_) = Primitive::from_json(&json)? else {
+            return Err(anyhow!(
+                "store file {} must contain a json object",
+                self.path.display()
+            ));
+        };
+        let Primitive::Ref(store) = store else {
+            return Err(anyhow!("BUG. store must be a ref"));
+        };
+        *store
+            .write()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))? = loaded;
+        self.last_json = Some(json);
+        Ok(())
+    }
+
+    pub fn snapshot_if_due(&mut self, store: &Primitive) -> anyhow::Result<()> {
+        if self.last_snapshot.elapsed() >= self.interval {
+            self.snapshot(store)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the store to a temporary file, then renames it,
+    /// so the store file is never left half written.
+    pub fn snapshot(&mut self, store: &Primitive) -> anyhow::Result<()> {
+        self.last_snapshot = Instant::now();
+        let json = store.to_json()?;
+        if self.last_json.as_ref() == Some(&json) {
+            return Ok(());
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(json.as_bytes())?;
+        // the content must be on disk before the rename replaces the previous file
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.last_json = Some(json);
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use std::{collections::BTreeMap, io::Write};
+
+use adana_script_core::{
+    Value,
+    primitive::{Compiler, Primitive, RefPrimitive},
+};
+use anyhow::anyhow;
+
+/// Function called in the background, with its immutable context.
+pub struct Callback {
+    function: Value,
+    ctx: BTreeMap<String, RefPrimitive>,
+}
+
+pub fn to_callback(function: Primitive, ctx: Option<Primitive>) -> anyhow::Result<Callback> {
+    let f @ Primitive::Function { .. } = function else {
+        return Err(anyhow!("second parameter must be a function"));
+    };
+    let ctx = match ctx {
+        Some(Primitive::Struct(ctx)) => ctx,
+        None => BTreeMap::new(),
+        Some(_) => {
+            return Err(anyhow!("third parameter must be the context (struct)"));
+        }
+    };
+    Ok(Callback {
+        function: f.to_value()?,
+        ctx: ctx
+            .into_iter()
+            .map(|(k, v)| (k, v.ref_prim()))
+            .collect::<BTreeMap<_, _>>(),
+    })
+}
+
+impl Callback {
+    /// Calls the function with the keys of the context as parameters.
+    pub fn call(&self, compiler: &mut Box<Compiler>) -> anyhow::Result<Primitive> {
+        let parameters = self.ctx.keys().cloned().map(Value::String).collect();
+        self.call_with(compiler, parameters)
+    }
+
+    /// A `Primitive::Error` returned by the function is an error too.
+    pub fn call_with(
+        &self,
+        compiler: &mut Box<Compiler>,
+        parameters: Vec<Value>,
+    ) -> anyhow::Result<Primitive> {
+        let res = compiler(
+            Value::FunctionCall {
+                parameters: Box::new(Value::BlockParen(parameters)),
+                function: Box::new(self.function.clone()),
+            },
+            self.ctx.clone(),
+        )?;
+        std::io::stdout().flush()?;
+        match res {
+            Primitive::Error(e) => Err(anyhow!(e)),
+            res => Ok(res),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Mutex, TryLockError,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender},
+    },
+    time::{Duration, Instant},
+};
+
+use adana_script_core::primitive::{LibData, Primitive};
+use anyhow::anyhow;
+
+/// How long a blocked recv holds the receiver before checking if it was closed.
+const RECV_SLICE: Duration = Duration::from_millis(10);
+
+#[derive(Clone)]
+enum Tx {
+    Bounded(SyncSender<Primitive>),
+    Unbounded(Sender<Primitive>),
+}
+
+/// Sending half, shared by every callback it's given to. None once closed.
+pub struct ChannelSender {
+    tx: Mutex<Option<Tx>>,
+}
+
+/// Receiving half, None once closed.
+pub struct ChannelReceiver {
+    rx: Mutex<Option<Receiver<Primitive>>>,
+    closed: AtomicBool,
+}
+
+fn lib_data<T: Send + Sync + 'static>(data: T) -> Primitive {
+    Primitive::LibData(LibData {
+        data: Arc::new(Box::new(data)),
+    })
+}
+
+/// `struct {sender, receiver}`, without capacity the channel is unbounded.
+pub fn new_channel(capacity: Option<usize>) -> Primitive {
+    let (tx, rx) = match capacity {
+        Some(capacity) => {
+            let (tx, rx) = mpsc::sync_channel(capacity);
+            (Tx::Bounded(tx), rx)
+        }
+        None => {
+            let (tx, rx) = mpsc::channel();
+            (Tx::Unbounded(tx), rx)
+        }
+    };
+    Primitive::Struct(BTreeMap::from([
+        (
+            "sender".to_string(),
+            lib_data(ChannelSender {
+                tx: Mutex::new(Some(tx)),
+            }),
+        ),
+        (
+            "receiver".to_string(),
+            lib_data(ChannelReceiver {
+                rx: Mutex::new(Some(rx)),
+                closed: AtomicBool::new(false),
+            }),
+        ),
+    ]))
+}
+
+fn downcast<'a, T: 'static>(param: Option<&'a Primitive>, name: &str) -> anyhow::Result<&'a T> {
+    match param {
+        Some(Primitive::LibData(lib_data)) => lib_data
+            .data
+            .downcast_ref::<T>()
+            .ok_or_else(|| anyhow!("invalid libData value. Must be a {name}")),
+        _ => Err(anyhow!("first parameter must be the {name}")),
+    }
+}
+
+pub fn get_sender(params: &[Primitive]) -> anyhow::Result<&ChannelSender> {
+    downcast(params.first(), "sender")
+}
+
+pub fn get_receiver(params: &[Primitive]) -> anyhow::Result<&ChannelReceiver> {
+    downcast(params.first(), "receiver")
+}
+
+impl ChannelSender {
+    /// Blocks while a bounded channel is full. False if the channel is closed.
+    pub fn send(&self, value: Primitive) -> anyhow::Result<bool> {
+        // cloned, so a blocked send doesn't prevent other senders from sending or closing
+        let tx = self
+            .tx
+            .lock()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))?
+            .clone();
+        let sent = match tx {
+            Some(Tx::Bounded(tx)) => tx.send(value).is_ok(),
+            Some(Tx::Unbounded(tx)) => tx.send(value).is_ok(),
+            None => false,
+        };
+        Ok(sent)
+    }
+
+    /// Receivers get the pending values, then null.
+    pub fn close(&self) -> anyhow::Result<()> {
+        self.tx
+            .lock()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))?
+            .take();
+        Ok(())
+    }
+}
+
+impl ChannelReceiver {
+    /// Waits in slices and releases the lock in between, so a blocked recv
+    /// doesn't prevent the receiver from being closed.
+    fn recv_until(&self, deadline: Option<Instant>) -> anyhow::Result<Primitive> {
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return Ok(Primitive::Null);
+            }
+            let slice = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Ok(Primitive::Null);
+                    }
+                    remaining.min(RECV_SLICE)
+                }
+                None => RECV_SLICE,
+            };
+            let rx = self
+                .rx
+                .lock()
+                .map_err(|e| anyhow!("could not acquire lock {e}"))?;
+            let Some(rx) = rx.as_ref() else {
+                return Ok(Primitive::Null);
+            };
+            match rx.recv_timeout(slice) {
+                Ok(value) => return Ok(value),
+                Err(RecvTimeoutError::Disconnected) => return Ok(Primitive::Null),
+                Err(RecvTimeoutError::Timeout) => (),
+            }
+        }
+    }
+
+    /// Next value, null once the channel is closed and empty.
+    pub fn recv(&self) -> anyhow::Result<Primitive> {
+        self.recv_until(None)
+    }
+
+    /// Next value if there is one, null otherwise.
+    pub fn try_recv(&self) -> anyhow::Result<Primitive> {
+        let rx = match self.rx.try_lock() {
+            Ok(rx) => rx,
+            // a blocked recv gets the next value first
+            Err(TryLockError::WouldBlock) => return Ok(Primitive::Null),
+            Err(TryLockError::Poisoned(e)) => return Err(anyhow!("could not acquire lock {e}")),
+        };
+        Ok(rx
+            .as_ref()
+            .and_then(|rx| rx.try_recv().ok())
+            .unwrap_or(Primitive::Null))
+    }
+
+    /// Next value, null if none came before the timeout.
+    pub fn recv_timeout(&self, timeout: Duration) -> anyhow::Result<Primitive> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    /// Senders get false from now on, a blocked recv returns null.
+    pub fn close(&self) -> anyhow::Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        self.rx
+            .lock()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))?
+            .take();
+        Ok(())
+    }
+}
+
+/// Closes a sender or a receiver.
+pub fn close_channel(params: &[Primitive]) -> anyhow::Result<()> {
+    if let Ok(sender) = get_sender(params) {
+        sender.close()
+    } else if let Ok(receiver) = get_receiver(params) {
+        receiver.close()
+    } else {
+        Err(anyhow!("first parameter must be a sender or a receiver"))
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStderr, ChildStdin, ChildStdout, ExitStatus, Stdio},
+    sync::{Arc, Mutex, TryLockError, mpsc},
+    time::Duration,
+};
+
+use adana_script_core::primitive::{LibData, Primitive};
+use anyhow::anyhow;
+
+use crate::exec::{ExecOptions, command, exit_code};
+
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A running child process, its pipes are locked independently so that
+/// reading stdout doesn't block writing to stdin.
+pub struct ChildHandle {
+    pid: u32,
+    child: Arc<Mutex<Child>>,
+    stdin: Arc<Mutex<Option<ChildStdin>>>,
+    stdout: Arc<Mutex<BufReader<ChildStdout>>>,
+    stderr: Arc<Mutex<BufReader<ChildStderr>>>,
+}
+
+pub fn spawn_child(cmd: &str, args: &[String], options: &ExecOptions) -> anyhow::Result<Primitive> {
+    let mut child = command(cmd, args, options)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("could not spawn {cmd}: {e}"))?;
+    let stdin = Arc::new(Mutex::new(child.stdin.take()));
+    if let Some(data) = options.stdin.clone() {
+        // written in the background, the child may fill stdout before reading all of it.
+        // Later writes wait for the lock, so they come after the data
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let stdin = stdin.clone();
+        std::thread::spawn(move || {
+            let Ok(mut stdin) = stdin.lock() else {
+                return;
+            };
+            let _ = locked_tx.send(());
+            if let Some(stdin) = stdin.as_mut() {
+                let _ = stdin.write_all(data.as_bytes());
+            }
+        });
+        let _ = locked_rx.recv();
+    }
+    let (Some(stdout), Some(stderr)) = (child.stdout.take(), child.stderr.take()) else {
+        return Err(anyhow!("BUG. stdout and stderr must be piped"));
+    };
+    Ok(Primitive::LibData(LibData {
+        data: Arc::new(Box::new(ChildHandle {
+            pid: child.id(),
+            child: Arc::new(Mutex::new(child)),
+            stdin,
+            stdout: Arc::new(Mutex::new(BufReader::new(stdout))),
+            stderr: Arc::new(Mutex::new(BufReader::new(stderr))),
+        })),
+    }))
+}
+
+pub fn get_child(params: &[Primitive]) -> anyhow::Result<&ChildHandle> {
+    match params.first() {
+        Some(Primitive::LibData(lib_data)) => lib_data
+            .data
+            .downcast_ref::<ChildHandle>()
+            .ok_or_else(|| anyhow!("invalid libData value. Must be a child process")),
+        _ => Err(anyhow!("first parameter must be the child process")),
+    }
+}
+
+fn to_status(status: ExitStatus) -> Primitive {
+    Primitive::Int(exit_code(status) as i128)
+}
+
+#[cfg(unix)]
+fn send_signal(pid: u32, signal: i32) -> std::io::Result<()> {
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_signal(_pid: u32, _signal: i32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "signals are not supported on this platform",
+    ))
+}
+
+fn read_line<R: BufRead>(reader: &Mutex<R>) -> anyhow::Result<Primitive> {
+    let mut line = String::new();
+    let read = reader
+        .lock()
+        .map_err(|e| anyhow!("could not acquire lock {e}"))?
+        .read_line(&mut line)?;
+    if read == 0 {
+        return Ok(Primitive::Null);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(Primitive::String(line))
+}
+
+impl ChildHandle {
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn write_stdin(&self, data: &str) -> anyhow::Result<()> {
+        let mut stdin = self
+            .stdin
+            .lock()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))?;
+        let Some(stdin) = stdin.as_mut() else {
+            return Err(anyhow!("stdin of process {} is closed", self.pid));
+        };
+        stdin.write_all(data.as_bytes())?;
+        stdin.flush()?;
+        Ok(())
+    }
+
+    /// Sends EOF to the child.
+    pub fn close_stdin(&self) -> anyhow::Result<()> {
+        self.stdin
+            .lock()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))?
+            .take();
+        Ok(())
+    }
+
+    /// Next line without the line break, null once the stream is closed.
+    pub fn read_stdout_line(&self) -> anyhow::Result<Primitive> {
+        read_line(&self.stdout)
+    }
+
+    pub fn read_stderr_line(&self) -> anyhow::Result<Primitive> {
+        read_line(&self.stderr)
+    }
+
+    /// Exit status if the child exited, null if it's still running.
+    pub fn try_wait(&self) -> anyhow::Result<Primitive> {
+        let status = self
+            .child
+            .lock()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))?
+            .try_wait()?;
+        Ok(status.map(to_status).unwrap_or(Primitive::Null))
+    }
+
+    /// Closes stdin unless a write is in progress (e.g the initial stdin, blocked
+    /// on a child that doesn't read it). True once stdin is closed.
+    fn try_close_stdin(&self) -> anyhow::Result<bool> {
+        match self.stdin.try_lock() {
+            Ok(mut stdin) => {
+                stdin.take();
+                Ok(true)
+            }
+            Err(TryLockError::WouldBlock) => Ok(false),
+            Err(TryLockError::Poisoned(e)) => Err(anyhow!("could not acquire lock {e}")),
+        }
+    }
+
+    pub fn wait(&self) -> anyhow::Result<Primitive> {
+        // polls, so the lock is released in between and the child can still be killed
+        let mut stdin_closed = false;
+        loop {
+            // EOF for the child, otherwise waiting for a process reading stdin never ends.
+            // Retried until the pending write is done, the child is reaped either way
+            if !stdin_closed {
+                stdin_closed = self.try_close_stdin()?;
+            }
+            if let Some(status) = self
+                .child
+                .lock()
+                .map_err(|e| anyhow!("could not acquire lock {e}"))?
+                .try_wait()?
+            {
+                return Ok(to_status(status));
+            }
+            std::thread::sleep(WAIT_POLL_INTERVAL);
+        }
+    }
+
+    pub fn kill(&self, signal: Option<i32>) -> anyhow::Result<()> {
+        let mut child = self
+            .child
+            .lock()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))?;
+        if child.try_wait()?.is_some() {
+            return Ok(());
+        }
+        match signal {
+            None => child.kill()?,
+            // the child is not reaped yet (we hold the lock), so the pid can't be reused
+            Some(signal) => send_signal(self.pid, signal)?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::mpsc::{self, Receiver},
+    time::{Duration, Instant},
+};
+
+use adana_script_core::primitive::Primitive;
+use anyhow::anyhow;
+
+use crate::to_duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+const GRACE_PERIOD: Duration = Duration::from_millis(100);
+
+/// Optional settings of a command, e.g `struct {cwd: "/tmp", env: struct {A: "1"}, stdin: "data", timeout: 1000}`
+#[derive(Debug, Default)]
+pub struct ExecOptions {
+    pub cwd: Option<PathBuf>,
+    pub env: BTreeMap<String, String>,
+    pub stdin: Option<String>,
+    pub timeout: Option<Duration>,
+}
+
+pub fn to_options(options: Option<&Primitive>) -> anyhow::Result<ExecOptions> {
+    let options = match options {
+        None | Some(Primitive::Null) => return Ok(ExecOptions::default()),
+        Some(Primitive::Ref(r)) => {
+            let r = r
+                .read()
+                .map_err(|e| anyhow!("could not acquire lock {e}"))?;
+            return to_options(Some(&r));
+        }
+        Some(Primitive::Struct(options)) => options,
+        Some(e) => return Err(anyhow!("options must be a struct => {e}")),
+    };
+    let cwd = match options.get("cwd") {
+        Some(Primitive::String(cwd)) => Some(PathBuf::from(cwd)),
+        Some(Primitive::Null) | None => None,
+        Some(e) => return Err(anyhow!("cwd must be a string => {e}")),
+    };
+    let env = match options.get("env") {
+        Some(Primitive::Struct(env)) => env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        Some(Primitive::Null) | None => BTreeMap::new(),
+        Some(e) => return Err(anyhow!("env must be a struct => {e}")),
+    };
+    let stdin = match options.get("stdin") {
+        Some(Primitive::String(stdin)) => Some(stdin.to_string()),
+        Some(Primitive::Null) | None => None,
+        Some(e) => return Err(anyhow!("stdin must be a string => {e}")),
+    };
+    let timeout = options.get("timeout").map(to_duration).transpose()?;
+    Ok(ExecOptions {
+        cwd,
+        env,
+        stdin,
+        timeout,
+    })
+}
+
+pub fn to_args(args: Option<&Primitive>) -> anyhow::Result<Vec<String>> {
+    match args {
+        None | Some(Primitive::Null) => Ok(vec![]),
+        Some(Primitive::Array(args)) => Ok(args.iter().map(|a| a.to_string()).collect()),
+        Some(e) => Err(anyhow!("args must be an array => {e}")),
+    }
+}
+
+pub fn command(cmd: &str, args: &[String], options: &ExecOptions) -> Command {
+    let mut command = Command::new(cmd);
+    command.args(args).envs(&options.env);
+    if let Some(cwd) = &options.cwd {
+        command.current_dir(cwd);
+    }
+    // its own process group, so that a timeout also kills what the command started
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    command
+}
+
+/// Kills the child and everything in its process group.
+#[cfg(unix)]
+fn kill_group(child: &mut Child) -> std::io::Result<()> {
+    // the child is the leader of its group, and not reaped yet so its pid can't be reused
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+        return child.kill();
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn kill_group(child: &mut Child) -> std::io::Result<()> {
+    child.kill()
+}
+
+pub fn error(kind: &str, message: String) -> Primitive {
+    Primitive::Struct(BTreeMap::from([
+        ("kind".to_string(), Primitive::String(kind.to_string())),
+        ("message".to_string(), Primitive::String(message)),
+    ]))
+}
+
+/// Reads the whole stream in the background, so a chatty child never blocks on a full pipe.
+pub fn drain<R: Read + Send + 'static>(stream: Option<R>) -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buf = vec![];
+        if let Some(mut stream) = stream {
+            let _ = stream.read_to_end(&mut buf);
+        }
+        let _ = tx.send(String::from_utf8_lossy(&buf).to_string());
+    });
+    rx
+}
+
+/// Output of a drained stream. After a timeout, a killed child may have left
+/// grandchildren holding the pipe open, so don't wait for them forever.
+pub fn collect(output: Receiver<String>, timed_out: bool) -> Primitive {
+    let output = if timed_out {
+        output.recv_timeout(GRACE_PERIOD).unwrap_or_default()
+    } else {
+        output.recv().unwrap_or_default()
+    };
+    Primitive::String(output)
+}
+
+/// Waits for the child, kills its process group once the timeout is reached. None means it timed out.
+pub fn wait(child: &mut Child, timeout: Option<Duration>) -> anyhow::Result<Option<ExitStatus>> {
+    let Some(timeout) = timeout else {
+        return Ok(Some(child.wait()?));
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            kill_group(child)?;
+            child.wait()?;
+            return Ok(None);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// `struct {status, stdout, stderr, duration}`, with an `error` struct when the command
+/// could not be started, exited with a non zero status or timed out.
+pub fn run(cmd: &str, args: &[String], options: &ExecOptions) -> Primitive {
+    let started = Instant::now();
+    let mut command = command(cmd, args, options);
+    command
+        .stdin(if options.stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            return Primitive::Struct(BTreeMap::from([
+                ("status".to_string(), Primitive::Null),
+                ("stdout".to_string(), Primitive::String(String::new())),
+                ("stderr".to_string(), Primitive::String(String::new())),
+                ("duration".to_string(), Primitive::Int(0)),
+                (
+                    "error".to_string(),
+                    error("spawn", format!("could not run {cmd}: {e}")),
+                ),
+            ]));
+        }
+    };
+    if let (Some(stdin), Some(mut child_stdin)) = (options.stdin.clone(), child.stdin.take()) {
+        std::thread::spawn(move || child_stdin.write_all(stdin.as_bytes()));
+    }
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+    let status = wait(&mut child, options.timeout);
+    let timed_out = matches!(status, Ok(None));
+
+    let mut res = BTreeMap::from([
+        ("stdout".to_string(), collect(stdout, timed_out)),
+        ("stderr".to_string(), collect(stderr, timed_out)),
+        (
+            "duration".to_string(),
+            Primitive::Int(started.elapsed().as_millis() as i128),
+        ),
+    ]);
+    let (status, err) = outcome(cmd, status, options.timeout);
+    res.insert("status".to_string(), status);
+    if let Some(err) = err {
+        res.insert("error".to_string(), err);
+    }
+    Primitive::Struct(res)
+}
+
+#[cfg(unix)]
+fn signal_of(status: ExitStatus) -> Option<i32> {
+    std::os::unix::process::ExitStatusExt::signal(&status)
+}
+
+#[cfg(not(unix))]
+fn signal_of(_status: ExitStatus) -> Option<i32> {
+    None
+}
+
+/// Exit code, or 128 + signal when the process was terminated by a signal (like shells do).
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| signal_of(status).map(|signal| 128 + signal))
+        .unwrap_or(-1)
+}
+
+/// Exit status, and the error struct if the command failed.
+pub fn outcome(
+    cmd: &str,
+    status: anyhow::Result<Option<ExitStatus>>,
+    timeout: Option<Duration>,
+) -> (Primitive, Option<Primitive>) {
+    match status {
+        Ok(Some(status)) => match (status.code(), signal_of(status)) {
+            (Some(0), _) => (Primitive::Int(0), None),
+            (Some(code), _) => (
+                Primitive::Int(code as i128),
+                Some(error("exit", format!("{cmd} exited with status {code}"))),
+            ),
+            (None, signal) => (
+                Primitive::Int(exit_code(status) as i128),
+                Some(error(
+                    "signal",
+                    match signal {
+                        Some(signal) => format!("{cmd} was terminated by signal {signal}"),
+                        None => format!("{cmd} was terminated by a signal"),
+                    },
+                )),
+            ),
+        },
+        Ok(None) => (
+            Primitive::Null,
+            Some(error(
+                "timeout",
+                format!(
+                    "{cmd} timed out after {}ms",
+                    timeout.unwrap_or_default().as_millis()
+                ),
+            )),
+        ),
+        Err(e) => (
+            Primitive::Null,
+            Some(error("wait", format!("could not wait for {cmd}: {e}"))),
+        ),
+    }
+}
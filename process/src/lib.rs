@@ -1,11 +1,125 @@
-use std::{collections::BTreeMap, io::Write, thread::JoinHandle, time::Duration};
-
-use adana_script_core::{
-    Value,
-    primitive::{Compiler, NativeFunctionCallResult, Primitive},
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    time::{Duration, Instant},
 };
 
-#[unsafe(no_mangle)]
+use adana_script_core::primitive::{Compiler, NativeFunctionCallResult, Primitive};
+use anyhow::anyhow;
+
+mod callback;
+mod channel;
+mod child;
+mod exec;
+mod par_map;
+mod pipeline;
+#[cfg(target_os = "linux")]
+mod procs;
+/// Processes are read from /proc, null where there is none.
+#[cfg(not(target_os = "linux"))]
+mod procs {
+    use adana_script_core::primitive::Primitive;
+
+    pub fn list() -> Primitive {
+        Primitive::Null
+    }
+
+    pub fn info(_pid: u32) -> Primitive {
+        Primitive::Null
+    }
+}
+#[cfg(unix)]
+mod signal;
+/// Signals only exist on unix.
+#[cfg(not(unix))]
+mod signal {
+    use adana_script_core::primitive::{Compiler, Primitive};
+    use anyhow::anyhow;
+
+    use crate::callback::Callback;
+
+    pub fn to_signal(_signal: &Primitive) -> anyhow::Result<i32> {
+        Err(anyhow!("signals are not supported on this platform"))
+    }
+
+    pub fn on_signal(
+        _signal: i32,
+        _callback: Callback,
+        _compiler: Box<Compiler>,
+    ) -> anyhow::Result<()> {
+        Err(anyhow!("signals are not supported on this platform"))
+    }
+
+    pub fn send_signal(_pid: i32, _signal: i32) -> anyhow::Result<()> {
+        Err(anyhow!("signals are not supported on this platform"))
+    }
+}
+#[cfg(unix)]
+mod sysinfo;
+/// Only implemented for unix, null elsewhere.
+#[cfg(not(unix))]
+mod sysinfo {
+    use adana_script_core::primitive::Primitive;
+
+    pub fn hostname() -> anyhow::Result<Primitive> {
+        Ok(Primitive::Null)
+    }
+
+    pub fn uptime() -> Primitive {
+        Primitive::Null
+    }
+
+    pub fn loadavg() -> Primitive {
+        Primitive::Null
+    }
+
+    pub fn cpu_count() -> Primitive {
+        Primitive::Null
+    }
+
+    pub fn memory() -> Primitive {
+        Primitive::Null
+    }
+
+    pub fn disk_usage(_path: &str) -> anyhow::Result<Primitive> {
+        Ok(Primitive::Null)
+    }
+
+    pub fn user() -> Primitive {
+        Primitive::Null
+    }
+}
+mod task;
+mod timer;
+use callback::to_callback;
+use channel::{close_channel, get_receiver, get_sender, new_channel};
+use child::{get_child, spawn_child};
+use exec::{run, to_args, to_options};
+use par_map::{par_map as run_par_map, to_threads};
+use pipeline::{run_pipeline, to_redirect};
+use signal::{on_signal as register_signal, send_signal as kill_pid, to_signal};
+use task::{spawn_task as start_task, to_task, wait_all};
+use timer::{get_timer, start_timer};
+
+fn to_duration(p: &Primitive) -> anyhow::Result<Duration> {
+    match p {
+        Primitive::Ref(r) => {
+            let r = r
+                .read()
+                .map_err(|e| anyhow!("could not acquire lock {e}"))?;
+            to_duration(&r)
+        }
+        Primitive::Int(ms) if *ms >= 0 => Ok(Duration::from_millis(*ms as u64)),
+        Primitive::U8(ms) => Ok(Duration::from_millis(*ms as u64)),
+        Primitive::I8(ms) if *ms >= 0 => Ok(Duration::from_millis(*ms as u64)),
+        e => Err(anyhow!(
+            "invalid duration, expected milliseconds (int) => {e}"
+        )),
+    }
+}
+
+// linked into the test executable, it would shadow the `environ` global of libc
+#[cfg_attr(not(test), unsafe(no_mangle))]
 pub fn environ(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
     if params.is_empty() {
         let s = std::env::vars()
@@ -20,64 +134,463 @@ pub fn environ(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFuncti
         Ok(r)
     }
 }
+
 #[unsafe(no_mangle)]
-pub fn delay(mut params: Vec<Primitive>, mut compiler: Box<Compiler>) -> NativeFunctionCallResult {
+pub fn set_env(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let [Primitive::String(key), value] = &params[..] else {
+        return Err(anyhow!("invalid params (e.g set_env(\"KEY\", \"value\"))"));
+    };
+    if key.is_empty() || key.contains(['=', '\0']) || value.to_string().contains('\0') {
+        return Err(anyhow!("invalid environment variable {key}"));
+    }
+    // SAFETY: variables are not synchronized with the libc, a native library reading the
+    // environment from another thread at the same time could see an inconsistent state
+    unsafe { std::env::set_var(key, value.to_string()) };
+    Ok(Primitive::Unit)
+}
+
+#[unsafe(no_mangle)]
+pub fn remove_env(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let [Primitive::String(key)] = &params[..] else {
+        return Err(anyhow!("invalid params (e.g remove_env(\"KEY\"))"));
+    };
+    if key.is_empty() || key.contains(['=', '\0']) {
+        return Err(anyhow!("invalid environment variable {key}"));
+    }
+    // SAFETY: see set_env
+    unsafe { std::env::remove_var(key) };
+    Ok(Primitive::Unit)
+}
+
+#[unsafe(no_mangle)]
+pub fn cwd(_params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    Ok(Primitive::String(
+        std::env::current_dir()?.display().to_string(),
+    ))
+}
+
+#[unsafe(no_mangle)]
+pub fn set_cwd(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let Some(Primitive::String(path)) = params.first() else {
+        return Err(anyhow!("invalid params (e.g set_cwd(\"/tmp\"))"));
+    };
+    std::env::set_current_dir(path)
+        .map_err(|e| anyhow!("could not change directory to {path}: {e}"))?;
+    Ok(Primitive::Unit)
+}
+
+/// Options of the adana binary followed by a value.
+const HOST_OPTIONS_WITH_VALUE: [&str; 10] = [
+    "-e",
+    "--execute",
+    "-sp",
+    "--script-path",
+    "-slp",
+    "--shared-lib-path",
+    "-db",
+    "--db",
+    "-hp",
+    "--history-path",
+];
+
+/// Index of the script path given to the host, either with `--script-path`
+/// or as its first positional argument. Stops at `--`.
+fn script_path_index(args: &[String]) -> Option<usize> {
+    let mut idx = 1;
+    while let Some(arg) = args.get(idx) {
+        match arg.as_str() {
+            "--" => return None,
+            "-sp" | "--script-path" => return Some(idx + 1).filter(|idx| *idx < args.len()),
+            option if HOST_OPTIONS_WITH_VALUE.contains(&option) => idx += 2,
+            flag if flag.starts_with('-') => idx += 1,
+            _ => return Some(idx),
+        }
+    }
+    None
+}
+
+/// Arguments after the script path, or after `--` when it comes first.
+fn script_args(args: Vec<String>) -> Vec<String> {
+    let start = script_path_index(&args).or_else(|| args.iter().position(|arg| arg == "--"));
+    match start {
+        Some(idx) => args.into_iter().skip(idx + 1).collect(),
+        None => vec![],
+    }
+}
+
+#[unsafe(no_mangle)]
+pub fn args(_params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    Ok(Primitive::Array(
+        script_args(std::env::args().collect())
+            .into_iter()
+            .map(Primitive::String)
+            .collect(),
+    ))
+}
+
+#[unsafe(no_mangle)]
+pub fn exit_process(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let code = match params.first() {
+        None | Some(Primitive::Null) => 0,
+        Some(Primitive::Int(code)) => i32::try_from(*code)?,
+        Some(Primitive::U8(code)) => *code as i32,
+        Some(Primitive::I8(code)) => *code as i32,
+        Some(e) => return Err(anyhow!("exit code must be an int => {e}")),
+    };
+    std::io::stdout().flush()?;
+    std::io::stderr().flush()?;
+    std::process::exit(code)
+}
+
+fn to_timer(
+    mut params: Vec<Primitive>,
+    compiler: Box<Compiler>,
+    repeat: bool,
+) -> NativeFunctionCallResult {
+    if params.len() < 2 || params.len() > 3 {
+        return Err(anyhow!(
+            "invalid params (e.g interval(1000, () => {{ println(\"tick\") }}, struct {{}}))"
+        ));
+    }
+    let delay = to_duration(&params.remove(0))
+        .map_err(|e| anyhow!("first parameter must be the duration. {e}"))?;
+    if repeat && delay.is_zero() {
+        return Err(anyhow!("interval must be greater than 0"));
+    }
+    let function = params.remove(0);
+    let callback = to_callback(function, params.pop())?;
+    Ok(start_timer(delay, repeat, callback, compiler))
+}
+
+#[unsafe(no_mangle)]
+pub fn delay(mut params: Vec<Primitive>, compiler: Box<Compiler>) -> NativeFunctionCallResult {
     if params.is_empty() {
-        Err(anyhow::anyhow!("at least one parameter must be provided"))
+        Err(anyhow!("at least one parameter must be provided"))
+    } else if params.len() == 1 {
+        let delay = to_duration(&params.remove(0))
+            .map_err(|e| anyhow!("first parameter must be the sleep duration. {e}"))?;
+        std::thread::sleep(delay);
+        Ok(Primitive::Unit)
     } else {
-        let delay = match params.remove(0) {
-            Primitive::Int(delay) if delay >= 0 => delay as u64,
-            Primitive::U8(delay) => delay as u64,
-            Primitive::I8(delay) if delay >= 0 => delay as u64,
-            e => {
-                return Err(anyhow::anyhow!(
-                    "first parameter must be the sleep duration (int) => {e}"
-                ));
-            }
-        };
+        to_timer(params, compiler, false)
+    }
+}
 
-        if params.is_empty() {
-            std::thread::sleep(Duration::from_millis(delay as u64));
-            Ok(Primitive::Unit)
-        } else if params.len() == 2 {
-            let handle: JoinHandle<anyhow::Result<()>> = std::thread::spawn(move || {
-                std::thread::sleep(Duration::from_millis(delay as u64));
+#[unsafe(no_mangle)]
+pub fn interval(params: Vec<Primitive>, compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    to_timer(params, compiler, true)
+}
 
-                let f @ Primitive::Function { .. } = params.remove(0) else {
-                    return Err(anyhow::anyhow!("second parameter must be a function"));
-                };
-                let Primitive::Struct(ctx) = params.remove(0) else {
-                    return Err(anyhow::anyhow!(
-                        "third parameter must be the context (struct)"
-                    ));
-                };
-                let function = f.to_value()?;
-                let ctx = ctx
-                    .into_iter()
-                    .map(|(k, v)| (k, v.ref_prim()))
-                    .collect::<BTreeMap<_, _>>();
-
-                let parameters = ctx.keys().cloned().map(Value::String).collect::<Vec<_>>();
-                compiler(
-                    Value::FunctionCall {
-                        parameters: Box::new(Value::BlockParen(parameters)),
-                        function: Box::new(function),
-                    },
-                    ctx,
-                )?;
-                std::io::stdout().flush()?;
-                Ok(())
-            });
-            std::thread::spawn(move || match handle.join() {
-                Ok(Ok(())) => {}
-                e => eprintln!("{e:?}"),
-            });
-            Ok(Primitive::Unit)
-        } else {
-            Err(anyhow::anyhow!("too many arguments"))
-        }
+#[unsafe(no_mangle)]
+pub fn cancel(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    Ok(Primitive::Bool(get_timer(&params)?.cancel()?))
+}
+
+#[unsafe(no_mangle)]
+pub fn timer_status(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    get_timer(&params)?.status()
+}
+
+#[unsafe(no_mangle)]
+pub fn spawn_task(mut params: Vec<Primitive>, compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    if params.is_empty() || params.len() > 2 {
+        return Err(anyhow!(
+            "invalid params (e.g spawn_task(() => {{ fs.read_file(path) }}, struct {{path: \"/tmp/a\"}}))"
+        ));
+    }
+    let function = params.remove(0);
+    let callback = to_callback(function, params.pop())
+        .map_err(|e| anyhow!("{e}. e.g spawn_task(function, [ctx])"))?;
+    Ok(start_task(callback, compiler))
+}
+
+/// The timeout is optional, null means no timeout.
+fn to_deadline(timeout: Option<&Primitive>) -> anyhow::Result<Option<Duration>> {
+    match timeout {
+        None | Some(Primitive::Null) => Ok(None),
+        Some(timeout) => to_duration(timeout).map(Some),
     }
 }
+
+#[unsafe(no_mangle)]
+pub fn r#await(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let Some(task) = params.first() else {
+        return Err(anyhow!("invalid params (e.g await(task, [timeout]))"));
+    };
+    let deadline = to_deadline(params.get(1))?.map(|timeout| Instant::now() + timeout);
+    to_task(task)?.wait(deadline)
+}
+
+#[unsafe(no_mangle)]
+pub fn await_all(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let Some(Primitive::Array(tasks)) = params.first() else {
+        return Err(anyhow!("invalid params (e.g await_all([task], [timeout]))"));
+    };
+    wait_all(tasks, to_deadline(params.get(1))?)
+}
+
+#[unsafe(no_mangle)]
+pub fn par_map(params: Vec<Primitive>, compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let (Some(Primitive::Array(items)), Some(function)) = (params.first(), params.get(1)) else {
+        return Err(anyhow!(
+            "invalid params (e.g par_map([1, 2, 3], (n) => {{ n * 2 }}, struct {{threads: 4}}))"
+        ));
+    };
+    let callback = to_callback(function.clone(), None)?;
+    let threads = to_threads(params.get(2))?;
+    run_par_map(items, &callback, threads, compiler)
+}
+
+#[unsafe(no_mangle)]
+pub fn channel(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let capacity = match params.first() {
+        None | Some(Primitive::Null) => None,
+        Some(Primitive::Int(capacity)) if *capacity >= 0 => Some(*capacity as usize),
+        Some(Primitive::U8(capacity)) => Some(*capacity as usize),
+        Some(e) => return Err(anyhow!("capacity must be a positive int => {e}")),
+    };
+    Ok(new_channel(capacity))
+}
+
+#[unsafe(no_mangle)]
+pub fn channel_send(
+    mut params: Vec<Primitive>,
+    _compiler: Box<Compiler>,
+) -> NativeFunctionCallResult {
+    if params.len() != 2 {
+        return Err(anyhow!("invalid params (e.g channel_send(sender, value))"));
+    }
+    let value = params.remove(1);
+    Ok(Primitive::Bool(get_sender(&params)?.send(value)?))
+}
+
+#[unsafe(no_mangle)]
+pub fn channel_recv(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    get_receiver(&params)?.recv()
+}
+
+#[unsafe(no_mangle)]
+pub fn channel_try_recv(
+    params: Vec<Primitive>,
+    _compiler: Box<Compiler>,
+) -> NativeFunctionCallResult {
+    get_receiver(&params)?.try_recv()
+}
+
+#[unsafe(no_mangle)]
+pub fn channel_recv_timeout(
+    params: Vec<Primitive>,
+    _compiler: Box<Compiler>,
+) -> NativeFunctionCallResult {
+    let Some(timeout) = params.get(1) else {
+        return Err(anyhow!(
+            "invalid params (e.g channel_recv_timeout(receiver, 1000))"
+        ));
+    };
+    let timeout = to_duration(timeout)?;
+    get_receiver(&params)?.recv_timeout(timeout)
+}
+
+#[unsafe(no_mangle)]
+pub fn channel_close(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    close_channel(&params)?;
+    Ok(Primitive::Unit)
+}
+
+#[unsafe(no_mangle)]
+pub fn on_signal(mut params: Vec<Primitive>, compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    if params.len() < 2 || params.len() > 3 {
+        return Err(anyhow!(
+            "invalid params (e.g on_signal(\"SIGTERM\", () => {{ http.stop(server) }}, struct {{server: server}}))"
+        ));
+    }
+    let signal = to_signal(&params.remove(0))?;
+    let function = params.remove(0);
+    let callback = to_callback(function, params.pop())?;
+    register_signal(signal, callback, compiler)?;
+    Ok(Primitive::Unit)
+}
+
+#[unsafe(no_mangle)]
+pub fn send_signal(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let [pid, signal] = &params[..] else {
+        return Err(anyhow!(
+            "invalid params (e.g send_signal(1234, \"SIGTERM\"))"
+        ));
+    };
+    let pid = match pid {
+        Primitive::Int(pid) => i32::try_from(*pid)?,
+        e => return Err(anyhow!("pid must be an int => {e}")),
+    };
+    kill_pid(pid, to_signal(signal)?)?;
+    Ok(Primitive::Unit)
+}
+
+#[unsafe(no_mangle)]
+pub fn hostname(_params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    sysinfo::hostname()
+}
+
+#[unsafe(no_mangle)]
+pub fn uptime(_params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    Ok(sysinfo::uptime())
+}
+
+#[unsafe(no_mangle)]
+pub fn loadavg(_params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    Ok(sysinfo::loadavg())
+}
+
+#[unsafe(no_mangle)]
+pub fn cpu_count(_params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    Ok(sysinfo::cpu_count())
+}
+
+#[unsafe(no_mangle)]
+pub fn memory(_params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    Ok(sysinfo::memory())
+}
+
+#[unsafe(no_mangle)]
+pub fn disk_usage(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    match params.first() {
+        None => sysinfo::disk_usage("/"),
+        Some(Primitive::String(path)) => sysinfo::disk_usage(path),
+        Some(e) => Err(anyhow!("path must be a string => {e}")),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub fn user(_params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    Ok(sysinfo::user())
+}
+
+#[unsafe(no_mangle)]
+pub fn list(_params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    Ok(procs::list())
+}
+
+#[unsafe(no_mangle)]
+pub fn info(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    match params.first() {
+        Some(Primitive::Int(pid)) => Ok(u32::try_from(*pid)
+            .map(procs::info)
+            .unwrap_or(Primitive::Null)),
+        _ => Err(anyhow!("invalid params (e.g info(1234))")),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub fn exec(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let Some(Primitive::String(cmd)) = params.first() else {
+        return Err(anyhow!(
+            "first parameter must be the command (e.g exec(\"ls\", [\"-l\"], struct {{cwd: \"/tmp\"}}))"
+        ));
+    };
+    let args = to_args(params.get(1))?;
+    let options = to_options(params.get(2))?;
+    Ok(run(cmd, &args, &options))
+}
+
+#[unsafe(no_mangle)]
+pub fn shell(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let Some(Primitive::String(script)) = params.first() else {
+        return Err(anyhow!(
+            "first parameter must be the shell command (e.g shell(\"ls -l | wc -l\"))"
+        ));
+    };
+    let options = to_options(params.get(1))?;
+    Ok(run("sh", &["-c".to_string(), script.to_string()], &options))
+}
+
+#[unsafe(no_mangle)]
+pub fn pipeline(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let Some(Primitive::Array(stages)) = params.first() else {
+        return Err(anyhow!(
+            "first parameter must be the stages (e.g pipeline([struct {{cmd: \"ls\"}}, struct {{cmd: \"wc\", args: [\"-l\"]}}]))"
+        ));
+    };
+    let options = to_options(params.get(1))?;
+    let redirect = to_redirect(params.get(1))?;
+    run_pipeline(stages, &options, &redirect)
+}
+
+#[unsafe(no_mangle)]
+pub fn spawn(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let Some(Primitive::String(cmd)) = params.first() else {
+        return Err(anyhow!(
+            "first parameter must be the command (e.g spawn(\"python3\", [\"-i\"]))"
+        ));
+    };
+    let args = to_args(params.get(1))?;
+    let options = to_options(params.get(2))?;
+    spawn_child(cmd, &args, &options)
+}
+
+#[unsafe(no_mangle)]
+pub fn pid(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    if params.is_empty() {
+        return Ok(Primitive::Int(std::process::id() as i128));
+    }
+    let child = get_child(&params)?;
+    Ok(Primitive::Int(child.pid() as i128))
+}
+
+#[unsafe(no_mangle)]
+pub fn write_stdin(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let child = get_child(&params)?;
+    let Some(data) = params.get(1) else {
+        return Err(anyhow!(
+            "invalid param (e.g write_stdin(child, \"data\\n\"))"
+        ));
+    };
+    child.write_stdin(&data.to_string())?;
+    Ok(Primitive::Unit)
+}
+
+#[unsafe(no_mangle)]
+pub fn close_stdin(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    get_child(&params)?.close_stdin()?;
+    Ok(Primitive::Unit)
+}
+
+#[unsafe(no_mangle)]
+pub fn read_stdout_line(
+    params: Vec<Primitive>,
+    _compiler: Box<Compiler>,
+) -> NativeFunctionCallResult {
+    get_child(&params)?.read_stdout_line()
+}
+
+#[unsafe(no_mangle)]
+pub fn read_stderr_line(
+    params: Vec<Primitive>,
+    _compiler: Box<Compiler>,
+) -> NativeFunctionCallResult {
+    get_child(&params)?.read_stderr_line()
+}
+
+#[unsafe(no_mangle)]
+pub fn try_wait_child(
+    params: Vec<Primitive>,
+    _compiler: Box<Compiler>,
+) -> NativeFunctionCallResult {
+    get_child(&params)?.try_wait()
+}
+
+#[unsafe(no_mangle)]
+pub fn wait_child(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    get_child(&params)?.wait()
+}
+
+#[unsafe(no_mangle)]
+pub fn kill_child(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let child = get_child(&params)?;
+    let signal = params.get(1).map(to_signal).transpose()?;
+    child.kill(signal)?;
+    Ok(Primitive::Unit)
+}
+
 /// Api description
 #[unsafe(no_mangle)]
 pub fn api_description(
@@ -90,11 +603,30 @@ pub fn api_description(
             "environ(string) -> struct | string, takes an optional key, return environment variable(s)"
                 .into(),
         )),
+        ("set_env".into(),
+        Primitive::String("set_env(string, any) -> () | set an environment variable".into())),
+        ("remove_env".into(),
+        Primitive::String("remove_env(string) -> () | remove an environment variable".into())),
+        ("cwd".into(),
+        Primitive::String("cwd() -> string | current working directory".into())),
+        ("set_cwd".into(),
+        Primitive::String("set_cwd(string) -> () | change the current working directory".into())),
+        ("args".into(),
+        Primitive::String(
+            r#"args() -> [string] | arguments passed to the script, after the script path
+            (or after --, when there is no script path before it).
+            e.g : adana script.adana foo -- bar => ["foo", "--", "bar"]
+                  adana -e "process.args()" -- foo => ["foo"]"#
+                .into(),
+        )),
+        ("exit_process".into(),
+        Primitive::String("exit_process([int]) -> ! | exit the process with the status code (0 by default)".into())),
         ("delay".into(),
         Primitive::String(
-            r#"delay(int, function, ctx) -> () | sleep for a specified amount of time. 
-            Takes optional function and immutable context (struct) as callback.
-            e.g : 
+            r#"delay(int, [function], [ctx]) -> () | timer | sleep for a specified amount of time.
+            With a function (and an optional immutable context struct), returns immediately
+            and calls it once after the delay, in the background. Returns a timer that can be cancelled.
+            e.g :
                f = () => {
                     e = process.environ("WEZTERM_PANE")
                     if(e!= null) {
@@ -102,9 +634,857 @@ pub fn api_description(
                     }
                }
                s = struct {}
-               process.delay(1000, f, s)
+               timer = process.delay(1000, f, s)
+               process.cancel(timer)
+               "#
+            .into(),
+        )),
+        ("interval".into(),
+        Primitive::String(
+            r#"interval(int, function, [ctx]) -> timer | call the function every int ms, until cancelled.
+            e.g :
+               timer = process.interval(1000, () => { println("tick") })
+               process.delay(5000)
+               process.cancel(timer)
+               "#
+            .into(),
+        )),
+        ("cancel".into(),
+        Primitive::String(
+            "cancel(timer) -> bool | cancel the timer, false if it was already done or cancelled".into(),
+        )),
+        ("timer_status".into(),
+        Primitive::String(
+            r#"timer_status(timer) -> struct | struct {running: bool, cancelled: bool, runs: int, errors: [string]}
+            errors are the last 100 errors of the callback"#
+                .into(),
+        )),
+        ("spawn_task".into(),
+        Primitive::String(
+            r#"spawn_task(function, [ctx]) -> task | call the function in the background.
+            Use await or await_all to get what it returned.
+            e.g :
+               a = process.spawn_task(() => { fs.read_file(path) }, struct {path: "/tmp/a.txt"})
+               b = process.spawn_task(() => { fs.read_file(path) }, struct {path: "/tmp/b.txt"})
+               results = process.await_all([a, b])
+               "#
+            .into(),
+        )),
+        ("await".into(),
+        Primitive::String(
+            r#"await(task, [timeout]) -> any | wait for the task and return what the function returned.
+            returns an error if the function failed or the timeout (ms) is reached first.
+            The task keeps running after a timeout and can be awaited again."#
+                .into(),
+        )),
+        ("await_all".into(),
+        Primitive::String(
+            r#"await_all([task], [timeout]) -> [any] | wait for every task, results are in the same order.
+            the timeout (ms) is shared by all the tasks"#
+                .into(),
+        )),
+        ("par_map".into(),
+        Primitive::String(
+            r#"par_map([any], function, [struct {threads}]) -> [any] | call the function with each element
+            on a pool of threads (defaults to the number of cpus), results are in the same order.
+            Fails with every error (and the index of its element) if any call failed.
+            The interpreter can't be shared between threads, so the calls run one at a time.
+            Callbacks that must run concurrently can use spawn_task and await_all instead,
+            each task has its own interpreter.
+            e.g : process.par_map([1, 2, 3], (n) => { n * 2 }, struct {threads: 2})
+               "#
+            .into(),
+        )),
+        ("channel".into(),
+        Primitive::String(
+            r#"channel([capacity]) -> struct {sender, receiver} | channel to pass values between callbacks.
+            Without capacity the channel is unbounded, otherwise send blocks while it's full.
+            e.g :
+               c = process.channel(10)
+               producer = process.spawn_task(() => {
+                    for i in 0..5 { process.channel_send(sender, i) }
+                    process.channel_close(sender)
+               }, struct {sender: c.sender})
+               v = process.channel_recv(c.receiver)
+               while(v != null) {
+                    println(v)
+                    v = process.channel_recv(c.receiver)
+               }
+               "#
+            .into(),
+        )),
+        ("channel_send".into(),
+        Primitive::String(
+            "channel_send(sender, any) -> bool | send a value, false if the channel is closed".into(),
+        )),
+        ("channel_recv".into(),
+        Primitive::String(
+            "channel_recv(receiver) -> any | next value (blocking), null once the channel is closed and empty".into(),
+        )),
+        ("channel_try_recv".into(),
+        Primitive::String("channel_try_recv(receiver) -> any | next value, null if there is none".into())),
+        ("channel_recv_timeout".into(),
+        Primitive::String(
+            "channel_recv_timeout(receiver, int) -> any | next value, null if none came within int ms".into(),
+        )),
+        ("channel_close".into(),
+        Primitive::String(
+            r#"channel_close(sender | receiver) -> () | close the channel.
+            receivers still get the pending values, senders get false"#
+                .into(),
+        )),
+        ("on_signal".into(),
+        Primitive::String(
+            r#"on_signal(signal, function, [ctx]) -> () | call the function when the process receives the signal,
+            instead of the default action (e.g exiting on SIGINT). Callbacks run one after the other on a
+            dedicated thread. signal is a name (e.g "SIGINT", "SIGTERM", "SIGHUP") or a number.
+            e.g :
+               server = http.new("0.0.0.0:8000")
+               process.on_signal("SIGTERM", () => { http.stop(server) }, struct {server: server})
                "#
             .into(),
         )),
+        ("send_signal".into(),
+        Primitive::String(
+            r#"send_signal(pid, signal) -> () | send a signal (e.g "SIGTERM" or 15) to a process"#.into(),
+        )),
+        ("hostname".into(),
+        Primitive::String("hostname() -> string | name of the host, null outside unix".into())),
+        ("uptime".into(),
+        Primitive::String("uptime() -> double | seconds since boot, null without /proc".into())),
+        ("loadavg".into(),
+        Primitive::String(
+            "loadavg() -> struct | struct {one, five, fifteen}, load averages over 1, 5 and 15 minutes".into(),
+        )),
+        ("cpu_count".into(),
+        Primitive::String("cpu_count() -> int | number of online cpus".into())),
+        ("memory".into(),
+        Primitive::String(
+            r#"memory() -> struct | struct {total, free, available, swap_total, swap_free} in bytes,
+            null without /proc"#
+                .into(),
+        )),
+        ("disk_usage".into(),
+        Primitive::String(
+            r#"disk_usage([path]) -> struct | struct {total, used, free, available} in bytes,
+            of the filesystem mounted at path ("/" by default)"#
+                .into(),
+        )),
+        ("user".into(),
+        Primitive::String(
+            "user() -> struct | struct {uid, gid, name, home} of the current user".into(),
+        )),
+        ("list".into(),
+        Primitive::String(
+            r#"list() -> [struct] | running processes, null without /proc.
+            struct {pid, ppid, name, cmdline: [string], state, rss: bytes, cpu_time: seconds,
+            start_time: seconds since the epoch}
+            state is R (running), S (sleeping), D (waiting on disk), Z (zombie), T (stopped)...
+            e.g :
+               for p in process.list() {
+                    if(p.name == "nginx") { println(p.pid) }
+               }
+               "#
+            .into(),
+        )),
+        ("info".into(),
+        Primitive::String(
+            r#"info(pid) -> struct | same as an entry of list, plus open_files (count) and cwd,
+            which are null when not permitted. null if there is no such process"#
+                .into(),
+        )),
+        ("exec".into(),
+        Primitive::String(
+            r#"exec(cmd, [args], [options]) -> struct | run a command and wait for it.
+            options: struct {cwd, env: struct, stdin: string, timeout: ms}
+            returns struct {status, stdout, stderr, duration}, with an error
+            struct {kind: "spawn" | "exit" | "signal" | "timeout", message} when it failed.
+            status is the exit code, 128 + signal if the command was killed by a signal,
+            or null if it could not be started or timed out.
+            on timeout, the command and the processes it started are killed.
+            e.g : process.exec("ls", ["-l"], struct {cwd: "/tmp", timeout: 1000})
+               "#
+            .into(),
+        )),
+        ("shell".into(),
+        Primitive::String(
+            r#"shell(string, [options]) -> struct | same as exec, but runs the string with sh -c.
+            e.g : process.shell("ls -l | wc -l")
+               "#
+            .into(),
+        )),
+        ("pipeline".into(),
+        Primitive::String(
+            r#"pipeline([stage], [options]) -> struct | run stages like a | b | c, without a shell.
+            stage: struct {cmd, args, [cwd], [env]}, stdin and timeout are only options of the pipeline
+            options: same as exec, plus struct {stdout_file, stderr_file, append: bool}
+            returns struct {status, stdout, duration, stages: [struct {cmd, status, stderr}]},
+            with the error of the first failing stage.
+            e.g : process.pipeline([struct {cmd: "cat", args: ["log.txt"]}, struct {cmd: "grep", args: ["ERROR"]}],
+                                   struct {stdout_file: "errors.txt", append: true})
+               "#
+            .into(),
+        )),
+        ("spawn".into(),
+        Primitive::String(
+            r#"spawn(cmd, [args], [options]) -> child | start a command without waiting for it.
+            options: struct {cwd, env: struct, stdin: string}. stdin, stdout and stderr are piped,
+            read the output so that the child doesn't block on a full pipe.
+            e.g :
+               child = process.spawn("python3", ["-i"])
+               process.write_stdin(child, "print(1 + 1)\n")
+               println(process.read_stdout_line(child))
+               process.kill_child(child, "SIGTERM")
+               "#
+            .into(),
+        )),
+        ("pid".into(),
+        Primitive::String("pid([child]) -> int | process id of the child, or of the current process".into())),
+        ("write_stdin".into(),
+        Primitive::String("write_stdin(child, string) -> () | write to the stdin of the child".into())),
+        ("close_stdin".into(),
+        Primitive::String("close_stdin(child) -> () | close the stdin of the child (EOF)".into())),
+        ("read_stdout_line".into(),
+        Primitive::String(
+            "read_stdout_line(child) -> string | next line of stdout (blocking), null once closed".into(),
+        )),
+        ("read_stderr_line".into(),
+        Primitive::String(
+            "read_stderr_line(child) -> string | next line of stderr (blocking), null once closed".into(),
+        )),
+        ("try_wait_child".into(),
+        Primitive::String(
+            "try_wait_child(child) -> int | exit status, null if the child is still running".into(),
+        )),
+        ("wait_child".into(),
+        Primitive::String(
+            r#"wait_child(child) -> int | close stdin and wait for the child to exit.
+            returns the exit status, or 128 + signal if it was killed"#
+                .into(),
+        )),
+        ("kill_child".into(),
+        Primitive::String(
+            r#"kill_child(child, [signal]) -> () | kill the child, or send it a signal (e.g "SIGTERM" or 15)"#
+                .into(),
+        )),
     ])))
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use adana_script_core::{
+        Value,
+        primitive::{Compiler, Primitive},
+    };
+
+    use crate::{
+        r#await, await_all, cancel, channel, channel_close, channel_recv, channel_recv_timeout,
+        channel_send, channel_try_recv, cpu_count, delay, disk_usage, environ, exec, hostname,
+        info, interval, kill_child, list, memory, on_signal, par_map, pid, pipeline,
+        read_stdout_line, remove_env, script_args, send_signal, set_env, shell, spawn, spawn_task,
+        timer_status, try_wait_child, user, wait_child, write_stdin,
+    };
+
+    fn compiler() -> Box<Compiler> {
+        Box::new(|_, _| Ok(Primitive::Unit))
+    }
+
+    fn string(s: &str) -> Primitive {
+        Primitive::String(s.to_string())
+    }
+
+    fn field(res: &Primitive, name: &str) -> Primitive {
+        let Primitive::Struct(res) = res else {
+            panic!("result should be a struct => {res}");
+        };
+        res.get(name).cloned().unwrap_or(Primitive::Null)
+    }
+
+    #[test]
+    fn exec_with_options() {
+        let options = Primitive::Struct(BTreeMap::from([
+            ("cwd".to_string(), string("/")),
+            (
+                "env".to_string(),
+                Primitive::Struct(BTreeMap::from([("GREETING".to_string(), string("hello"))])),
+            ),
+            ("stdin".to_string(), string("from stdin")),
+        ]));
+        let res = exec(
+            vec![
+                string("sh"),
+                Primitive::Array(vec![string("-c"), string("echo $GREETING $(pwd) $(cat)")]),
+                options,
+            ],
+            compiler(),
+        )
+        .unwrap();
+        assert_eq!(field(&res, "stdout").to_string(), "hello / from stdin\n");
+        assert!(matches!(field(&res, "status"), Primitive::Int(0)));
+        assert!(matches!(field(&res, "error"), Primitive::Null));
+    }
+
+    #[test]
+    fn shell_errors() {
+        let res = shell(vec![string("echo oops >&2; exit 3")], compiler()).unwrap();
+        assert!(matches!(field(&res, "status"), Primitive::Int(3)));
+        assert_eq!(field(&res, "stderr").to_string(), "oops\n");
+        assert_eq!(field(&field(&res, "error"), "kind").to_string(), "exit");
+
+        let timeout = Primitive::Struct(BTreeMap::from([(
+            "timeout".to_string(),
+            Primitive::Int(50),
+        )]));
+        let res = shell(vec![string("sleep 5"), timeout], compiler()).unwrap();
+        assert!(matches!(field(&res, "status"), Primitive::Null));
+        assert_eq!(field(&field(&res, "error"), "kind").to_string(), "timeout");
+
+        let res = shell(vec![string("kill -TERM $$")], compiler()).unwrap();
+        assert!(matches!(field(&res, "status"), Primitive::Int(143)));
+        assert_eq!(field(&field(&res, "error"), "kind").to_string(), "signal");
+
+        let res = exec(vec![string("surely-not-a-command")], compiler()).unwrap();
+        assert_eq!(field(&field(&res, "error"), "kind").to_string(), "spawn");
+    }
+
+    #[test]
+    fn timeout_kills_process_group() {
+        let timeout = Primitive::Struct(BTreeMap::from([(
+            "timeout".to_string(),
+            Primitive::Int(100),
+        )]));
+        let res = shell(vec![string("sleep 5 & echo $!; wait"), timeout], compiler()).unwrap();
+        assert_eq!(field(&field(&res, "error"), "kind").to_string(), "timeout");
+        // the background sleep holds stdout open until it's killed as well
+        let stdout = field(&res, "stdout").to_string();
+        assert!(stdout.trim().parse::<u32>().is_ok(), "{stdout:?}");
+        assert!(matches!(field(&res, "duration"), Primitive::Int(d) if d < 1000));
+    }
+
+    #[test]
+    fn interactive_child() {
+        let child = spawn(vec![string("cat")], compiler()).unwrap();
+        let params = |extra: &[Primitive]| {
+            let mut params = vec![child.clone()];
+            params.extend_from_slice(extra);
+            params
+        };
+        write_stdin(params(&[string("hello\n")]), compiler()).unwrap();
+        assert_eq!(
+            read_stdout_line(params(&[]), compiler())
+                .unwrap()
+                .to_string(),
+            "hello"
+        );
+        assert!(matches!(
+            try_wait_child(params(&[]), compiler()).unwrap(),
+            Primitive::Null
+        ));
+        assert!(matches!(
+            wait_child(params(&[]), compiler()).unwrap(),
+            Primitive::Int(0)
+        ));
+        assert!(matches!(
+            read_stdout_line(params(&[]), compiler()).unwrap(),
+            Primitive::Null
+        ));
+
+        // the background sleep keeps stdin open without reading it (through fd 3, sh gives
+        // background jobs /dev/null), so the initial write only ends when sleep exits
+        let options = Primitive::Struct(BTreeMap::from([(
+            "stdin".to_string(),
+            string(&"x".repeat(1 << 20)),
+        )]));
+        let child = spawn(
+            vec![
+                string("sh"),
+                Primitive::Array(vec![
+                    string("-c"),
+                    string("exec 3<&0; sleep 1 <&3 & exit 3"),
+                ]),
+                options,
+            ],
+            compiler(),
+        )
+        .unwrap();
+        let started = std::time::Instant::now();
+        assert!(matches!(
+            wait_child(vec![child], compiler()).unwrap(),
+            Primitive::Int(3)
+        ));
+        assert!(started.elapsed() < Duration::from_millis(900));
+
+        // more than the pipe buffers hold, cat blocks on stdout until it's read
+        let input = "line\n".repeat(100_000);
+        let options = Primitive::Struct(BTreeMap::from([("stdin".to_string(), string(&input))]));
+        let child = spawn(
+            vec![string("cat"), Primitive::Array(vec![]), options],
+            compiler(),
+        )
+        .unwrap();
+        for _ in 0..100_000 {
+            assert_eq!(
+                read_stdout_line(vec![child.clone()], compiler())
+                    .unwrap()
+                    .to_string(),
+                "line"
+            );
+        }
+        assert!(matches!(
+            wait_child(vec![child], compiler()).unwrap(),
+            Primitive::Int(0)
+        ));
+
+        let child = spawn(
+            vec![string("sleep"), Primitive::Array(vec![string("5")])],
+            compiler(),
+        )
+        .unwrap();
+        kill_child(vec![child.clone(), string("SIGTERM")], compiler()).unwrap();
+        assert!(matches!(
+            wait_child(vec![child], compiler()).unwrap(),
+            Primitive::Int(143)
+        ));
+    }
+
+    #[test]
+    fn pipeline_to_file() {
+        let stage = |cmd: &str, args: &[&str]| {
+            Primitive::Struct(BTreeMap::from([
+                ("cmd".to_string(), string(cmd)),
+                (
+                    "args".to_string(),
+                    Primitive::Array(args.iter().map(|a| string(a)).collect()),
+                ),
+            ]))
+        };
+        let stages = Primitive::Array(vec![
+            stage("printf", &["b\\na\\nb\\n"]),
+            stage("sort", &[]),
+            stage("uniq", &["-c"]),
+            stage("wc", &["-l"]),
+        ]);
+        let res = pipeline(vec![stages.clone()], compiler()).unwrap();
+        assert_eq!(field(&res, "stdout").to_string().trim(), "2");
+        let Primitive::Array(stages_res) = field(&res, "stages") else {
+            panic!("stages should be an array");
+        };
+        assert_eq!(stages_res.len(), 4);
+
+        let out = std::env::temp_dir().join(format!("adana-pipeline-{}.txt", std::process::id()));
+        let options = Primitive::Struct(BTreeMap::from([
+            (
+                "stdout_file".to_string(),
+                string(&out.display().to_string()),
+            ),
+            ("append".to_string(), Primitive::Bool(true)),
+        ]));
+        for _ in 0..2 {
+            pipeline(vec![stages.clone(), options.clone()], compiler()).unwrap();
+        }
+        assert_eq!(
+            std::fs::read_to_string(&out)
+                .unwrap()
+                .split_whitespace()
+                .collect::<Vec<_>>(),
+            ["2", "2"]
+        );
+        std::fs::remove_file(&out).unwrap();
+
+        let res = pipeline(
+            vec![Primitive::Array(vec![
+                stage("sh", &["-c", "exit 2"]),
+                stage("cat", &[]),
+            ])],
+            compiler(),
+        )
+        .unwrap();
+        assert!(matches!(field(&res, "status"), Primitive::Int(0)));
+        assert_eq!(field(&field(&res, "error"), "kind").to_string(), "exit");
+
+        let Primitive::Struct(mut with_timeout) = stage("cat", &[]) else {
+            unreachable!()
+        };
+        with_timeout.insert("timeout".to_string(), Primitive::Int(100));
+        let err = pipeline(
+            vec![Primitive::Array(vec![Primitive::Struct(with_timeout)])],
+            compiler(),
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("stdin and timeout can only be set")
+        );
+    }
+
+    #[test]
+    fn timers() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let counting: Box<Compiler> = Box::new(move |_, _| {
+            if counter.fetch_add(1, Ordering::SeqCst) == 1 {
+                Ok(Primitive::Error("second call failed".to_string()))
+            } else {
+                Ok(Primitive::Unit)
+            }
+        });
+        let function = Primitive::Function {
+            parameters: vec![],
+            exprs: vec![],
+        };
+        let timer = interval(
+            vec![
+                Primitive::Int(10),
+                function.clone(),
+                Primitive::Struct(BTreeMap::new()),
+            ],
+            counting,
+        )
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(matches!(
+            cancel(vec![timer.clone()], compiler()).unwrap(),
+            Primitive::Bool(true)
+        ));
+        std::thread::sleep(Duration::from_millis(30));
+        let runs = calls.load(Ordering::SeqCst);
+        assert!(runs >= 3, "interval ran {runs} times");
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(calls.load(Ordering::SeqCst), runs);
+
+        let status = timer_status(vec![timer.clone()], compiler()).unwrap();
+        assert!(matches!(field(&status, "running"), Primitive::Bool(false)));
+        assert!(matches!(field(&status, "cancelled"), Primitive::Bool(true)));
+        assert_eq!(field(&status, "runs").to_string(), runs.to_string());
+        let Primitive::Array(errors) = field(&status, "errors") else {
+            panic!("errors should be an array");
+        };
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            cancel(vec![timer], compiler()).unwrap(),
+            Primitive::Bool(false)
+        ));
+
+        let timer = delay(vec![Primitive::Int(10_000), function], compiler()).unwrap();
+        assert!(matches!(
+            cancel(vec![timer.clone()], compiler()).unwrap(),
+            Primitive::Bool(true)
+        ));
+        std::thread::sleep(Duration::from_millis(20));
+        let status = timer_status(vec![timer], compiler()).unwrap();
+        assert!(matches!(field(&status, "running"), Primitive::Bool(false)));
+        assert_eq!(field(&status, "runs").to_string(), "0");
+
+        assert!(
+            delay(
+                vec![Primitive::Int(10), string("not a function")],
+                compiler()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn tasks() {
+        // returns the n of the context after n * 10ms, fails for n = 2
+        let compiler = || -> Box<Compiler> {
+            Box::new(|_, ctx| {
+                let n = match ctx.get("n").map(|n| n.read().unwrap().clone()) {
+                    Some(Primitive::Int(n)) => n,
+                    _ => panic!("missing n"),
+                };
+                std::thread::sleep(Duration::from_millis(n as u64 * 10));
+                if n == 2 {
+                    Ok(Primitive::Error("two failed".to_string()))
+                } else {
+                    Ok(Primitive::Int(n))
+                }
+            })
+        };
+        let task = |n: i128| {
+            spawn_task(
+                vec![
+                    Primitive::Function {
+                        parameters: vec![],
+                        exprs: vec![],
+                    },
+                    Primitive::Struct(BTreeMap::from([("n".to_string(), Primitive::Int(n))])),
+                ],
+                compiler(),
+            )
+            .unwrap()
+        };
+        // each task has its own interpreter, they run concurrently
+        let started = std::time::Instant::now();
+        let sleeping = (0..4).map(|_| task(10)).collect::<Vec<_>>();
+        await_all(vec![Primitive::Array(sleeping)], compiler()).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(300));
+
+        let tasks = vec![task(3), task(1), task(2)];
+        let Primitive::Array(results) =
+            await_all(vec![Primitive::Array(tasks.clone())], compiler()).unwrap()
+        else {
+            panic!("results should be an array");
+        };
+        assert_eq!(results[0].to_string(), "3");
+        assert_eq!(results[1].to_string(), "1");
+        assert!(matches!(&results[2], Primitive::Error(e) if e == "two failed"));
+        // results are kept
+        assert_eq!(
+            r#await(vec![tasks[0].clone()], compiler())
+                .unwrap()
+                .to_string(),
+            "3"
+        );
+
+        let slow = task(20);
+        assert!(matches!(
+            r#await(vec![slow.clone(), Primitive::Int(10)], compiler()).unwrap(),
+            Primitive::Error(_)
+        ));
+        assert_eq!(r#await(vec![slow], compiler()).unwrap().to_string(), "20");
+
+        assert!(spawn_task(vec![string("not a function")], compiler()).is_err());
+    }
+
+    #[test]
+    fn par_map_in_order() {
+        // doubles the element, fails on 3
+        let double = || -> Box<Compiler> {
+            Box::new(|value, _| {
+                let Value::FunctionCall { parameters, .. } = value else {
+                    panic!("expected a function call");
+                };
+                let Value::BlockParen(parameters) = *parameters else {
+                    panic!("expected parameters");
+                };
+                match parameters.first() {
+                    Some(Value::Primitive(Primitive::Int(3))) => {
+                        Ok(Primitive::Error("three failed".to_string()))
+                    }
+                    Some(Value::Primitive(Primitive::Int(n))) => Ok(Primitive::Int(n * 2)),
+                    _ => panic!("expected an int"),
+                }
+            })
+        };
+        let function = Primitive::Function {
+            parameters: vec![],
+            exprs: vec![],
+        };
+        let options =
+            Primitive::Struct(BTreeMap::from([("threads".to_string(), Primitive::Int(3))]));
+        let items =
+            |range: std::ops::Range<i128>| Primitive::Array(range.map(Primitive::Int).collect());
+
+        let res = par_map(
+            vec![items(4..20), function.clone(), options.clone()],
+            double(),
+        )
+        .unwrap();
+        let expected = (4..20).map(|n| (n * 2).to_string()).collect::<Vec<_>>();
+        let Primitive::Array(res) = res else {
+            panic!("result should be an array");
+        };
+        assert_eq!(
+            res.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+            expected
+        );
+
+        let err = par_map(vec![items(0..5), function.clone(), options], double()).unwrap_err();
+        assert_eq!(err.to_string(), "1 of 5 calls failed:\n[3] three failed");
+
+        assert!(matches!(
+            par_map(vec![Primitive::Array(vec![]), function], double()).unwrap(),
+            Primitive::Array(res) if res.is_empty()
+        ));
+    }
+
+    #[test]
+    fn channels() {
+        let c = channel(vec![Primitive::Int(2)], compiler()).unwrap();
+        let (sender, receiver) = (field(&c, "sender"), field(&c, "receiver"));
+        let producer = {
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                for i in 0..5 {
+                    let sent =
+                        channel_send(vec![sender.clone(), Primitive::Int(i)], compiler()).unwrap();
+                    assert!(matches!(sent, Primitive::Bool(true)));
+                }
+                channel_close(vec![sender], compiler()).unwrap();
+            })
+        };
+        let mut received = vec![];
+        loop {
+            match channel_recv(vec![receiver.clone()], compiler()).unwrap() {
+                Primitive::Null => break,
+                v => received.push(v.to_string()),
+            }
+        }
+        producer.join().unwrap();
+        assert_eq!(received, ["0", "1", "2", "3", "4"]);
+        assert!(matches!(
+            channel_send(vec![sender, Primitive::Int(5)], compiler()).unwrap(),
+            Primitive::Bool(false)
+        ));
+
+        let c = channel(vec![], compiler()).unwrap();
+        let (sender, receiver) = (field(&c, "sender"), field(&c, "receiver"));
+        assert!(matches!(
+            channel_try_recv(vec![receiver.clone()], compiler()).unwrap(),
+            Primitive::Null
+        ));
+        assert!(matches!(
+            channel_recv_timeout(vec![receiver.clone(), Primitive::Int(10)], compiler()).unwrap(),
+            Primitive::Null
+        ));
+        channel_send(vec![sender.clone(), string("hello")], compiler()).unwrap();
+        assert_eq!(
+            channel_try_recv(vec![receiver.clone()], compiler())
+                .unwrap()
+                .to_string(),
+            "hello"
+        );
+        // a blocked recv doesn't prevent polling or closing the receiver
+        let blocked = {
+            let receiver = receiver.clone();
+            std::thread::spawn(move || channel_recv(vec![receiver], compiler()).unwrap())
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(matches!(
+            channel_try_recv(vec![receiver.clone()], compiler()).unwrap(),
+            Primitive::Null
+        ));
+        channel_close(vec![receiver], compiler()).unwrap();
+        assert!(matches!(blocked.join().unwrap(), Primitive::Null));
+        assert!(matches!(
+            channel_send(vec![sender, string("hello")], compiler()).unwrap(),
+            Primitive::Bool(false)
+        ));
+        assert!(channel_close(vec![string("not a channel")], compiler()).is_err());
+    }
+
+    #[test]
+    fn environment() {
+        set_env(
+            vec![string("ADANA_STD_TEST"), Primitive::Int(42)],
+            compiler(),
+        )
+        .unwrap();
+        assert_eq!(
+            environ(vec![string("ADANA_STD_TEST")], compiler())
+                .unwrap()
+                .to_string(),
+            "42"
+        );
+        remove_env(vec![string("ADANA_STD_TEST")], compiler()).unwrap();
+        assert!(matches!(
+            environ(vec![string("ADANA_STD_TEST")], compiler()).unwrap(),
+            Primitive::Null
+        ));
+        assert!(set_env(vec![string("A=B"), string("c")], compiler()).is_err());
+
+        assert_eq!(
+            pid(vec![], compiler()).unwrap().to_string(),
+            std::process::id().to_string()
+        );
+
+        let args = |args: &[&str]| script_args(args.iter().map(|a| a.to_string()).collect());
+        assert_eq!(
+            args(&["adana", "s.adana", "run", "--", "-x"]),
+            ["run", "--", "-x"]
+        );
+        assert_eq!(args(&["adana", "-im", "-sp", "s.adana", "a"]), ["a"]);
+        assert_eq!(args(&["adana", "-slp", "/lib", "s.adana", "a"]), ["a"]);
+        assert_eq!(args(&["adana", "-e", "1 + 1", "--", "a", "b"]), ["a", "b"]);
+        assert_eq!(args(&["adana", "--", "s.adana", "a"]), ["s.adana", "a"]);
+        assert!(args(&["adana", "s.adana"]).is_empty());
+        assert!(args(&["adana", "-sp"]).is_empty());
+        assert!(args(&["adana"]).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signals() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let counting: Box<Compiler> = Box::new(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(Primitive::Unit)
+        });
+        let function = Primitive::Function {
+            parameters: vec![],
+            exprs: vec![],
+        };
+        on_signal(vec![string("SIGUSR2"), function.clone()], counting).unwrap();
+        let pid = Primitive::Int(std::process::id() as i128);
+        for _ in 0..2 {
+            send_signal(vec![pid.clone(), string("USR2")], compiler()).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        assert!(on_signal(vec![string("SIGKILL"), function], compiler()).is_err());
+        assert!(send_signal(vec![Primitive::Int(0), string("SIGTERM")], compiler()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn system_info() {
+        assert!(!hostname(vec![], compiler()).unwrap().to_string().is_empty());
+        assert!(matches!(cpu_count(vec![], compiler()).unwrap(), Primitive::Int(n) if n > 0));
+
+        let disk = disk_usage(vec![string("/")], compiler()).unwrap();
+        assert!(matches!(field(&disk, "total"), Primitive::Int(n) if n > 0));
+        assert!(disk_usage(vec![string("/does/not/exist")], compiler()).is_err());
+
+        let user = user(vec![], compiler()).unwrap();
+        assert_eq!(
+            field(&user, "uid").to_string(),
+            unsafe { libc::getuid() }.to_string()
+        );
+
+        #[cfg(target_os = "linux")]
+        {
+            let memory = memory(vec![], compiler()).unwrap();
+            assert!(matches!(field(&memory, "total"), Primitive::Int(n) if n > 0));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn process_list() {
+        let pid = std::process::id() as i128;
+        let Primitive::Array(processes) = list(vec![], compiler()).unwrap() else {
+            panic!("list should be an array");
+        };
+        assert!(
+            processes
+                .iter()
+                .any(|p| field(p, "pid").to_string() == pid.to_string())
+        );
+
+        let current = info(vec![Primitive::Int(pid)], compiler()).unwrap();
+        assert!(matches!(field(&current, "ppid"), Primitive::Int(_)));
+        assert!(matches!(field(&current, "rss"), Primitive::Int(n) if n > 0));
+        assert!(matches!(field(&current, "open_files"), Primitive::Int(n) if n > 0));
+        assert_eq!(
+            field(&current, "cwd").to_string(),
+            std::env::current_dir().unwrap().display().to_string()
+        );
+        assert!(matches!(field(&current, "cmdline"), Primitive::Array(args) if !args.is_empty()));
+        assert!(matches!(
+            info(vec![Primitive::Int(-1)], compiler()).unwrap(),
+            Primitive::Null
+        ));
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use adana_script_core::{
+    Value,
+    primitive::{Compiler, Primitive},
+};
+use anyhow::anyhow;
+
+use crate::callback::Callback;
+
+/// Number of threads of the pool, `struct {threads}`, defaults to the available parallelism.
+pub fn to_threads(options: Option<&Primitive>) -> anyhow::Result<usize> {
+    let threads = match options {
+        None | Some(Primitive::Null) => None,
+        Some(Primitive::Struct(options)) => options.get("threads"),
+        Some(e) => return Err(anyhow!("options must be a struct => {e}")),
+    };
+    match threads {
+        None | Some(Primitive::Null) => Ok(std::thread::available_parallelism()
+            .map(NonZeroUsize::get)
+            .unwrap_or(1)),
+        Some(Primitive::Int(threads)) if *threads > 0 => Ok(*threads as usize),
+        Some(Primitive::U8(threads)) if *threads > 0 => Ok(*threads as usize),
+        Some(e) => Err(anyhow!("threads must be greater than 0 => {e}")),
+    }
+}
+
+/// Calls the function with each item on a pool of `threads` workers, results are in the
+/// order of the items. There is a single interpreter and it can't be shared, so the
+/// callbacks run one at a time: each worker takes the lock for the whole call.
+pub fn par_map(
+    items: &[Primitive],
+    callback: &Callback,
+    threads: usize,
+    compiler: Box<Compiler>,
+) -> anyhow::Result<Primitive> {
+    let compiler = Mutex::new(compiler);
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; items.len()]);
+    std::thread::scope(|scope| {
+        for _ in 0..threads.min(items.len()) {
+            scope.spawn(|| {
+                loop {
+                    let idx = next.fetch_add(1, Ordering::SeqCst);
+                    let Some(item) = items.get(idx) else {
+                        break;
+                    };
+                    let res = match compiler.lock() {
+                        Ok(mut compiler) => callback
+                            .call_with(&mut compiler, vec![Value::Primitive(item.clone())])
+                            .map_err(|e| e.to_string()),
+                        Err(e) => Err(format!("could not acquire lock {e}")),
+                    };
+                    if let Ok(mut results) = results.lock() {
+                        results[idx] = Some(res);
+                    }
+                }
+            });
+        }
+    });
+
+    let results = results
+        .into_inner()
+        .map_err(|e| anyhow!("could not acquire lock {e}"))?;
+    let mut values = Vec::with_capacity(results.len());
+    let mut errors = vec![];
+    for (idx, res) in results.into_iter().enumerate() {
+        match res {
+            Some(Ok(value)) => values.push(value),
+            Some(Err(e)) => errors.push(format!("[{idx}] {e}")),
+            None => errors.push(format!("[{idx}] not called")),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(anyhow!(
+            "{} of {} calls failed:\n{}",
+            errors.len(),
+            items.len(),
+            errors.join("\n")
+        ));
+    }
+    Ok(Primitive::Array(values))
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    process::{Child, Stdio},
+    time::Instant,
+};
+
+use adana_script_core::primitive::Primitive;
+use anyhow::anyhow;
+
+use crate::exec::{
+    ExecOptions, collect, command, drain, error, outcome, to_args, to_options, wait,
+};
+
+/// Where the output of the pipeline goes, instead of being captured.
+#[derive(Debug, Default)]
+pub struct Redirect {
+    stdout_file: Option<String>,
+    stderr_file: Option<String>,
+    append: bool,
+}
+
+pub fn to_redirect(options: Option<&Primitive>) -> anyhow::Result<Redirect> {
+    let Some(Primitive::Struct(options)) = options else {
+        return Ok(Redirect::default());
+    };
+    let file = |name: &str| match options.get(name) {
+        Some(Primitive::String(path)) => Ok(Some(path.to_string())),
+        Some(Primitive::Null) | None => Ok(None),
+        Some(e) => Err(anyhow!("{name} must be a path => {e}")),
+    };
+    Ok(Redirect {
+        stdout_file: file("stdout_file")?,
+        stderr_file: file("stderr_file")?,
+        append: matches!(options.get("append"), Some(Primitive::Bool(true))),
+    })
+}
+
+struct Stage {
+    cmd: String,
+    args: Vec<String>,
+    options: ExecOptions,
+}
+
+/// A stage is `struct {cmd, args}`, and can override cwd and env of the pipeline.
+/// stdin and timeout are rejected: the first stage reads the stdin of the pipeline,
+/// and the timeout applies to the pipeline as a whole.
+fn to_stage(stage: &Primitive, pipeline: &ExecOptions) -> anyhow::Result<Stage> {
+    let Primitive::Struct(fields) = stage else {
+        return Err(anyhow!(
+            "stage must be a struct (e.g struct {{cmd: \"grep\", args: [\"foo\"]}}) => {stage}"
+        ));
+    };
+    let Some(Primitive::String(cmd)) = fields.get("cmd") else {
+        return Err(anyhow!("missing cmd in stage {stage}"));
+    };
+    let mut options = to_options(Some(stage))?;
+    if options.stdin.is_some() || options.timeout.is_some() {
+        return Err(anyhow!(
+            "stdin and timeout can only be set on the pipeline, not on a stage => {stage}"
+        ));
+    }
+    options.cwd = options.cwd.or_else(|| pipeline.cwd.clone());
+    for (k, v) in &pipeline.env {
+        options
+            .env
+            .entry(k.to_string())
+            .or_insert_with(|| v.to_string());
+    }
+    Ok(Stage {
+        cmd: cmd.to_string(),
+        args: to_args(fields.get("args"))?,
+        options,
+    })
+}
+
+fn open(path: &str, append: bool) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .map_err(|e| anyhow!("could not open {path}: {e}"))
+}
+
+/// Runs `a | b | c`, each stdout feeding the stdin of the next stage, without a shell.
+/// Returns `struct {status, stdout, duration, stages: [struct {cmd, status, stderr}]}`,
+/// with the `error` of the first failing stage, if any.
+pub fn run_pipeline(
+    stages: &[Primitive],
+    options: &ExecOptions,
+    redirect: &Redirect,
+) -> anyhow::Result<Primitive> {
+    if stages.is_empty() {
+        return Err(anyhow!("pipeline must have at least one stage"));
+    }
+    let stages = stages
+        .iter()
+        .map(|s| to_stage(s, options))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let stdout_file = redirect
+        .stdout_file
+        .as_ref()
+        .map(|path| open(path, redirect.append))
+        .transpose()?;
+    let stderr_file = redirect
+        .stderr_file
+        .as_ref()
+        .map(|path| open(path, redirect.append))
+        .transpose()?;
+
+    let started = Instant::now();
+    let mut children: Vec<Child> = vec![];
+    let mut previous_stdout = None;
+    for (idx, stage) in stages.iter().enumerate() {
+        let is_last = idx + 1 == stages.len();
+        let mut command = command(&stage.cmd, &stage.args, &stage.options);
+        command.stdin(match previous_stdout.take() {
+            Some(stdout) => Stdio::from(stdout),
+            None if options.stdin.is_some() => Stdio::piped(),
+            None => Stdio::null(),
+        });
+        command.stdout(match (&stdout_file, is_last) {
+            (Some(file), true) => Stdio::from(file.try_clone()?),
+            _ => Stdio::piped(),
+        });
+        command.stderr(match &stderr_file {
+            Some(file) => Stdio::from(file.try_clone()?),
+            None => Stdio::piped(),
+        });
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                for mut child in children {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+                return Ok(Primitive::Struct(BTreeMap::from([
+                    ("status".to_string(), Primitive::Null),
+                    ("stdout".to_string(), Primitive::String(String::new())),
+                    ("stages".to_string(), Primitive::Array(vec![])),
+                    ("duration".to_string(), Primitive::Int(0)),
+                    (
+                        "error".to_string(),
+                        error("spawn", format!("could not run {}: {e}", stage.cmd)),
+                    ),
+                ])));
+            }
+        };
+        if idx == 0 {
+            if let (Some(data), Some(mut stdin)) = (options.stdin.clone(), child.stdin.take()) {
+                std::thread::spawn(move || stdin.write_all(data.as_bytes()));
+            }
+        }
+        if !is_last {
+            previous_stdout = child.stdout.take();
+        }
+        children.push(child);
+    }
+
+    let Some(last) = children.last_mut() else {
+        return Err(anyhow!("BUG. pipeline without children"));
+    };
+    let stdout = drain(last.stdout.take());
+    let stderrs = children
+        .iter_mut()
+        .map(|child| drain(child.stderr.take()))
+        .collect::<Vec<_>>();
+
+    let deadline = options.timeout.map(|timeout| started + timeout);
+    let mut results = vec![];
+    let mut first_error = None;
+    let mut timed_out = false;
+    for ((stage, mut child), stderr) in stages.iter().zip(children).zip(stderrs) {
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let status = wait(&mut child, remaining);
+        timed_out |= matches!(status, Ok(None));
+        let (status, err) = outcome(&stage.cmd, status, options.timeout);
+        let mut result = BTreeMap::from([
+            ("cmd".to_string(), Primitive::String(stage.cmd.to_string())),
+            ("status".to_string(), status),
+            ("stderr".to_string(), collect(stderr, timed_out)),
+        ]);
+        if let Some(err) = err {
+            first_error.get_or_insert_with(|| err.clone());
+            result.insert("error".to_string(), err);
+        }
+        results.push(Primitive::Struct(result));
+    }
+
+    let status = match results.last() {
+        Some(Primitive::Struct(last)) => last.get("status").cloned().unwrap_or(Primitive::Null),
+        _ => Primitive::Null,
+    };
+    let mut res = BTreeMap::from([
+        ("status".to_string(), status),
+        ("stdout".to_string(), collect(stdout, timed_out)),
+        ("stages".to_string(), Primitive::Array(results)),
+        (
+            "duration".to_string(),
+            Primitive::Int(started.elapsed().as_millis() as i128),
+        ),
+    ]);
+    if let Some(err) = first_error {
+        res.insert("error".to_string(), err);
+    }
+    Ok(Primitive::Struct(res))
+}
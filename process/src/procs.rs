@@ -0,0 +1,125 @@
+use std::{collections::BTreeMap, path::Path};
+
+use adana_script_core::primitive::Primitive;
+
+/// Fields of /proc/<pid>/stat, after the name. Indexes are the field numbers of proc(5) minus 3.
+const STATE: usize = 0;
+const PPID: usize = 1;
+const UTIME: usize = 11;
+const STIME: usize = 12;
+const START_TIME: usize = 19;
+const RSS: usize = 21;
+
+fn clock_ticks() -> f64 {
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as f64,
+        _ => 100.0,
+    }
+}
+
+fn page_size() -> u64 {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as u64,
+        _ => 4096,
+    }
+}
+
+/// Boot time in seconds since the epoch, start times are relative to it.
+fn boot_time() -> Option<u64> {
+    std::fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// `struct {pid, ppid, name, cmdline, state, rss, cpu_time, start_time}`, None if the process
+/// is gone (or was never there).
+fn read_process(pid: u32, boot_time: Option<u64>) -> Option<BTreeMap<String, Primitive>> {
+    let dir = Path::new("/proc").join(pid.to_string());
+    let stat = std::fs::read_to_string(dir.join("stat")).ok()?;
+    // the name is between parentheses and may contain spaces and parentheses itself
+    let (name, fields) = stat.split_once('(')?.1.rsplit_once(')')?;
+    let fields = fields.split_whitespace().collect::<Vec<_>>();
+    let number = |idx: usize| fields.get(idx).and_then(|f| f.parse::<u64>().ok());
+
+    let ticks = clock_ticks();
+    let cpu_time = match (number(UTIME), number(STIME)) {
+        (Some(utime), Some(stime)) => Primitive::Double((utime + stime) as f64 / ticks),
+        _ => Primitive::Null,
+    };
+    let start_time = match (boot_time, number(START_TIME)) {
+        (Some(boot_time), Some(start)) => {
+            Primitive::Double(boot_time as f64 + start as f64 / ticks)
+        }
+        _ => Primitive::Null,
+    };
+    let cmdline = std::fs::read(dir.join("cmdline"))
+        .unwrap_or_default()
+        .split(|b| *b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| Primitive::String(String::from_utf8_lossy(arg).to_string()))
+        .collect();
+    let to_int = |n: Option<u64>| {
+        n.map(|n| Primitive::Int(n as i128))
+            .unwrap_or(Primitive::Null)
+    };
+
+    Some(BTreeMap::from([
+        ("pid".to_string(), Primitive::Int(pid as i128)),
+        ("ppid".to_string(), to_int(number(PPID))),
+        ("name".to_string(), Primitive::String(name.to_string())),
+        ("cmdline".to_string(), Primitive::Array(cmdline)),
+        (
+            "state".to_string(),
+            fields
+                .get(STATE)
+                .map(|state| Primitive::String(state.to_string()))
+                .unwrap_or(Primitive::Null),
+        ),
+        (
+            "rss".to_string(),
+            to_int(number(RSS).map(|pages| pages * page_size())),
+        ),
+        ("cpu_time".to_string(), cpu_time),
+        ("start_time".to_string(), start_time),
+    ]))
+}
+
+/// Every process visible in /proc.
+pub fn list() -> Primitive {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Primitive::Null;
+    };
+    let boot_time = boot_time();
+    let mut pids = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .collect::<Vec<_>>();
+    pids.sort_unstable();
+    Primitive::Array(
+        pids.into_iter()
+            // processes may exit while listing
+            .filter_map(|pid| read_process(pid, boot_time))
+            .map(Primitive::Struct)
+            .collect(),
+    )
+}
+
+/// Same as an entry of list, with `open_files` and `cwd`, which are null when not permitted.
+pub fn info(pid: u32) -> Primitive {
+    let Some(mut process) = read_process(pid, boot_time()) else {
+        return Primitive::Null;
+    };
+    let dir = Path::new("/proc").join(pid.to_string());
+    let open_files = std::fs::read_dir(dir.join("fd"))
+        .map(|fds| Primitive::Int(fds.count() as i128))
+        .unwrap_or(Primitive::Null);
+    let cwd = std::fs::read_link(dir.join("cwd"))
+        .map(|cwd| Primitive::String(cwd.display().to_string()))
+        .unwrap_or(Primitive::Null);
+    process.insert("open_files".to_string(), open_files);
+    process.insert("cwd".to_string(), cwd);
+    Primitive::Struct(process)
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicI32, Ordering},
+    },
+};
+
+use adana_script_core::primitive::{Compiler, Primitive};
+use anyhow::anyhow;
+
+use crate::callback::Callback;
+
+const SIGNALS: [(&str, i32); 12] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+];
+
+/// Signal number from its name (e.g `"SIGTERM"` or `"TERM"`) or its number.
+pub fn to_signal(signal: &Primitive) -> anyhow::Result<i32> {
+    match signal {
+        Primitive::Int(n) if *n > 0 && *n < 65 => Ok(*n as i32),
+        Primitive::U8(n) if *n > 0 && *n < 65 => Ok(*n as i32),
+        Primitive::String(name) => {
+            let name = name.to_uppercase();
+            let name = name.strip_prefix("SIG").unwrap_or(&name);
+            SIGNALS
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, signal)| *signal)
+                .ok_or_else(|| anyhow!("unknown signal {name}"))
+        }
+        e => Err(anyhow!("invalid signal (e.g \"SIGTERM\" or 15) => {e}")),
+    }
+}
+
+struct Handler {
+    callback: Callback,
+    compiler: Box<Compiler>,
+}
+
+type Handlers = BTreeMap<i32, Vec<Arc<Mutex<Handler>>>>;
+
+/// Callbacks registered by on_signal, by signal number.
+static HANDLERS: LazyLock<Mutex<Handlers>> = LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// Write end of the pipe the signal handler notifies the dispatcher thread through.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "emscripten",
+    target_os = "hurd",
+    target_os = "redox",
+    target_os = "dragonfly"
+))]
+fn errno_location() -> *mut libc::c_int {
+    unsafe { libc::__errno_location() }
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "cygwin"
+))]
+fn errno_location() -> *mut libc::c_int {
+    unsafe { libc::__errno() }
+}
+
+#[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+fn errno_location() -> *mut libc::c_int {
+    unsafe { libc::__error() }
+}
+
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+fn errno_location() -> *mut libc::c_int {
+    unsafe { libc::___errno() }
+}
+
+/// Only async-signal-safe calls in there, the callbacks run on the dispatcher thread.
+extern "C" fn notify(signal: libc::c_int) {
+    // the interrupted code may be about to read errno, write can change it
+    let errno = errno_location();
+    let saved = unsafe { *errno };
+    let byte = signal as u8;
+    let fd = PIPE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        // nothing to do if the pipe is full, the dispatcher is already behind
+        unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+    }
+    unsafe { *errno = saved };
+}
+
+fn dispatch(read_fd: libc::c_int) {
+    let mut byte = 0u8;
+    loop {
+        let read = unsafe { libc::read(read_fd, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+        if read < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+            continue;
+        }
+        if read <= 0 {
+            break;
+        }
+        let handlers = match HANDLERS.lock() {
+            Ok(handlers) => handlers.get(&(byte as i32)).cloned().unwrap_or_default(),
+            Err(_) => break,
+        };
+        // the registry is not locked while the callbacks run, so they can call on_signal
+        for handler in handlers {
+            let Ok(mut handler) = handler.lock() else {
+                continue;
+            };
+            let Handler { callback, compiler } = &mut *handler;
+            if let Err(e) = callback.call(compiler) {
+                eprintln!("{e:?}");
+            }
+        }
+    }
+}
+
+/// Creates the pipe and starts the dispatcher thread, once.
+fn start_dispatcher() -> anyhow::Result<()> {
+    static STARTED: Mutex<bool> = Mutex::new(false);
+    let mut started = STARTED
+        .lock()
+        .map_err(|e| anyhow!("could not acquire lock {e}"))?;
+    if *started {
+        return Ok(());
+    }
+    // pipe2 is not available everywhere (e.g macOS), the flags are set with fcntl
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let [read_fd, write_fd] = fds;
+    for fd in fds {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    // only the write end must not block, in the signal handler
+    if unsafe { libc::fcntl(write_fd, libc::F_SETFL, libc::O_NONBLOCK) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    PIPE.store(write_fd, Ordering::SeqCst);
+    std::thread::Builder::new()
+        .name("adana-signals".to_string())
+        .spawn(move || dispatch(read_fd))?;
+    *started = true;
+    Ok(())
+}
+
+/// Calls the callback every time the process receives the signal, instead of the default action.
+pub fn on_signal(signal: i32, callback: Callback, compiler: Box<Compiler>) -> anyhow::Result<()> {
+    if signal == libc::SIGKILL || signal == libc::SIGSTOP {
+        return Err(anyhow!("SIGKILL and SIGSTOP cannot be handled"));
+    }
+    if signal > u8::MAX as i32 {
+        return Err(anyhow!("invalid signal {signal}"));
+    }
+    start_dispatcher()?;
+    HANDLERS
+        .lock()
+        .map_err(|e| anyhow!("could not acquire lock {e}"))?
+        .entry(signal)
+        .or_default()
+        .push(Arc::new(Mutex::new(Handler { callback, compiler })));
+
+    let handler: extern "C" fn(libc::c_int) = notify;
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = handler as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+    if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+pub fn send_signal(pid: i32, signal: i32) -> anyhow::Result<()> {
+    if pid <= 0 {
+        // 0 and negative pids target process groups, not what a script means by a pid
+        return Err(anyhow!("invalid pid {pid}"));
+    }
+    if unsafe { libc::kill(pid, signal) } != 0 {
+        return Err(anyhow!(
+            "could not send signal {signal} to {pid}: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
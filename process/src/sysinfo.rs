@@ -0,0 +1,148 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{CStr, CString},
+};
+
+use adana_script_core::primitive::Primitive;
+use anyhow::anyhow;
+
+/// Content of a /proc file.
+#[cfg(target_os = "linux")]
+fn read_proc(path: &str) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+}
+
+/// There is no /proc.
+#[cfg(not(target_os = "linux"))]
+fn read_proc(_path: &str) -> Option<String> {
+    None
+}
+
+fn int(n: u64) -> Primitive {
+    Primitive::Int(n as i128)
+}
+
+pub fn hostname() -> anyhow::Result<Primitive> {
+    let mut buf = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // not guaranteed to be terminated if the name was truncated
+    buf[buf.len() - 1] = 0;
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    Ok(Primitive::String(name.to_string_lossy().to_string()))
+}
+
+/// Seconds since boot.
+pub fn uptime() -> Primitive {
+    read_proc("/proc/uptime")
+        .and_then(|uptime| uptime.split_whitespace().next()?.parse::<f64>().ok())
+        .map(Primitive::Double)
+        .unwrap_or(Primitive::Null)
+}
+
+/// `struct {one, five, fifteen}`, the load averages over 1, 5 and 15 minutes.
+pub fn loadavg() -> Primitive {
+    let mut loads = [0f64; 3];
+    if unsafe { libc::getloadavg(loads.as_mut_ptr(), 3) } != 3 {
+        return Primitive::Null;
+    }
+    let [one, five, fifteen] = loads;
+    Primitive::Struct(BTreeMap::from([
+        ("one".to_string(), Primitive::Double(one)),
+        ("five".to_string(), Primitive::Double(five)),
+        ("fifteen".to_string(), Primitive::Double(fifteen)),
+    ]))
+}
+
+/// Online cpus, not only the ones this process may run on.
+pub fn cpu_count() -> Primitive {
+    match unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } {
+        n if n > 0 => int(n as u64),
+        _ => Primitive::Null,
+    }
+}
+
+/// `struct {total, free, available, swap_total, swap_free}` in bytes.
+pub fn memory() -> Primitive {
+    let Some(meminfo) = read_proc("/proc/meminfo") else {
+        return Primitive::Null;
+    };
+    // lines like `MemTotal:       16316412 kB`
+    let values = meminfo
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            let kb = value.split_whitespace().next()?.parse::<u64>().ok()?;
+            Some((key, kb * 1024))
+        })
+        .collect::<BTreeMap<_, _>>();
+    let field = |name: &str, key: &str| {
+        (
+            name.to_string(),
+            values.get(key).copied().map(int).unwrap_or(Primitive::Null),
+        )
+    };
+    Primitive::Struct(BTreeMap::from([
+        field("total", "MemTotal"),
+        field("free", "MemFree"),
+        field("available", "MemAvailable"),
+        field("swap_total", "SwapTotal"),
+        field("swap_free", "SwapFree"),
+    ]))
+}
+
+/// `struct {total, used, free, available}` in bytes, of the filesystem mounted at path.
+/// available is what unprivileged users can use, free includes the reserved blocks.
+// the statvfs fields are u32 or u64 depending on the platform
+#[allow(clippy::unnecessary_cast)]
+pub fn disk_usage(path: &str) -> anyhow::Result<Primitive> {
+    let c_path = CString::new(path)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(anyhow!(
+            "could not get disk usage of {path}: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let block = stat.f_frsize as u64;
+    let total = stat.f_blocks as u64 * block;
+    let free = stat.f_bfree as u64 * block;
+    Ok(Primitive::Struct(BTreeMap::from([
+        ("total".to_string(), int(total)),
+        ("used".to_string(), int(total.saturating_sub(free))),
+        ("free".to_string(), int(free)),
+        ("available".to_string(), int(stat.f_bavail as u64 * block)),
+    ])))
+}
+
+/// `struct {uid, gid, name, home}` of the current user, name and home are null when the
+/// user has no entry in the password database (e.g in some containers).
+pub fn user() -> Primitive {
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let mut res = BTreeMap::from([
+        ("uid".to_string(), int(uid as u64)),
+        ("gid".to_string(), int(gid as u64)),
+        ("name".to_string(), Primitive::Null),
+        ("home".to_string(), Primitive::Null),
+    ]);
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut entry: *mut libc::passwd = std::ptr::null_mut();
+    let found =
+        unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut entry) } == 0
+            && !entry.is_null();
+    if found {
+        let to_string =
+            |s: *const libc::c_char| unsafe { CStr::from_ptr(s) }.to_string_lossy().to_string();
+        res.insert(
+            "name".to_string(),
+            Primitive::String(to_string(passwd.pw_name)),
+        );
+        res.insert(
+            "home".to_string(),
+            Primitive::String(to_string(passwd.pw_dir)),
+        );
+    }
+    Primitive::Struct(res)
+}
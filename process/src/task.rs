@@ -0,0 +1,97 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use adana_script_core::primitive::{Compiler, LibData, Primitive};
+use anyhow::anyhow;
+
+use crate::callback::Callback;
+
+#[derive(Default)]
+struct TaskState {
+    /// None while the task is running
+    result: Mutex<Option<Result<Primitive, String>>>,
+    done: Condvar,
+}
+
+pub struct Task {
+    state: Arc<TaskState>,
+}
+
+/// Runs the callback in the background, its result is kept until the task is dropped.
+pub fn spawn_task(callback: Callback, mut compiler: Box<Compiler>) -> Primitive {
+    let state = Arc::new(TaskState::default());
+    let task_state = state.clone();
+    std::thread::spawn(move || {
+        let result = callback.call(&mut compiler).map_err(|e| e.to_string());
+        if let Ok(mut guard) = task_state.result.lock() {
+            *guard = Some(result);
+        }
+        task_state.done.notify_all();
+    });
+    Primitive::LibData(LibData {
+        data: Arc::new(Box::new(Task { state })),
+    })
+}
+
+pub fn to_task(task: &Primitive) -> anyhow::Result<&Task> {
+    match task {
+        Primitive::LibData(lib_data) => lib_data
+            .data
+            .downcast_ref::<Task>()
+            .ok_or_else(|| anyhow!("invalid libData value. Must be a task")),
+        e => Err(anyhow!("expected a task => {e}")),
+    }
+}
+
+impl Task {
+    /// Result of the callback, or an error if the deadline is reached first.
+    /// The task keeps running after a timeout and can be awaited again.
+    pub fn wait(&self, deadline: Option<Instant>) -> anyhow::Result<Primitive> {
+        let mut result = self
+            .state
+            .result
+            .lock()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))?;
+        loop {
+            match result.as_ref() {
+                Some(Ok(res)) => return Ok(res.clone()),
+                Some(Err(e)) => return Ok(Primitive::Error(e.to_string())),
+                None => {}
+            }
+            result = match deadline {
+                None => self
+                    .state
+                    .done
+                    .wait(result)
+                    .map_err(|e| anyhow!("could not acquire lock {e}"))?,
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(Primitive::Error("task timed out".to_string()));
+                    }
+                    self.state
+                        .done
+                        .wait_timeout(result, deadline - now)
+                        .map_err(|e| anyhow!("could not acquire lock {e}"))?
+                        .0
+                }
+            };
+        }
+    }
+}
+
+/// Waits for every task, sharing the same timeout. Results are in the order of the tasks.
+pub fn wait_all(tasks: &[Primitive], timeout: Option<Duration>) -> anyhow::Result<Primitive> {
+    let tasks = tasks
+        .iter()
+        .map(to_task)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let results = tasks
+        .into_iter()
+        .map(|task| task.wait(deadline))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(Primitive::Array(results))
+}
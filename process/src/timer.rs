@@ -0,0 +1,145 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use adana_script_core::primitive::{Compiler, LibData, Primitive};
+use anyhow::anyhow;
+
+use crate::callback::Callback;
+
+/// Errors kept per timer, the oldest are dropped first.
+const MAX_ERRORS: usize = 100;
+
+#[derive(Default)]
+struct TimerState {
+    cancelled: Mutex<bool>,
+    wake: Condvar,
+    running: AtomicBool,
+    runs: AtomicU64,
+    errors: Mutex<Vec<String>>,
+}
+
+pub struct Timer {
+    state: Arc<TimerState>,
+}
+
+impl TimerState {
+    /// Sleeps until the deadline, returns false if the timer was cancelled meanwhile.
+    fn sleep_until(&self, deadline: Instant) -> bool {
+        let Ok(mut cancelled) = self.cancelled.lock() else {
+            return false;
+        };
+        loop {
+            if *cancelled {
+                return false;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            match self.wake.wait_timeout(cancelled, deadline - now) {
+                Ok((guard, _)) => cancelled = guard,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    fn run(&self, callback: &Callback, compiler: &mut Box<Compiler>) {
+        self.runs.fetch_add(1, Ordering::SeqCst);
+        if let Err(e) = callback.call(compiler) {
+            eprintln!("{e:?}");
+            if let Ok(mut errors) = self.errors.lock() {
+                if errors.len() == MAX_ERRORS {
+                    errors.remove(0);
+                }
+                errors.push(e.to_string());
+            }
+        }
+    }
+}
+
+/// Calls the callback once after `delay`, or every `delay` if `repeat` is set, until cancelled.
+pub fn start_timer(
+    delay: Duration,
+    repeat: bool,
+    callback: Callback,
+    mut compiler: Box<Compiler>,
+) -> Primitive {
+    let state = Arc::new(TimerState::default());
+    state.running.store(true, Ordering::SeqCst);
+    let timer_state = state.clone();
+    std::thread::spawn(move || {
+        let state = timer_state;
+        let mut deadline = Instant::now() + delay;
+        while state.sleep_until(deadline) {
+            state.run(&callback, &mut compiler);
+            if !repeat {
+                break;
+            }
+            // fixed rate, unless the callback took longer than the period
+            deadline = (deadline + delay).max(Instant::now());
+        }
+        state.running.store(false, Ordering::SeqCst);
+    });
+    Primitive::LibData(LibData {
+        data: Arc::new(Box::new(Timer { state })),
+    })
+}
+
+pub fn get_timer(params: &[Primitive]) -> anyhow::Result<&Timer> {
+    match params.first() {
+        Some(Primitive::LibData(lib_data)) => lib_data
+            .data
+            .downcast_ref::<Timer>()
+            .ok_or_else(|| anyhow!("invalid libData value. Must be a timer")),
+        _ => Err(anyhow!("first parameter must be the timer")),
+    }
+}
+
+impl Timer {
+    /// Returns false if the timer was already done or cancelled.
+    pub fn cancel(&self) -> anyhow::Result<bool> {
+        let mut cancelled = self
+            .state
+            .cancelled
+            .lock()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))?;
+        let was_pending = !*cancelled && self.state.running.load(Ordering::SeqCst);
+        *cancelled = true;
+        self.state.wake.notify_all();
+        Ok(was_pending)
+    }
+
+    pub fn status(&self) -> anyhow::Result<Primitive> {
+        let cancelled = *self
+            .state
+            .cancelled
+            .lock()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))?;
+        let errors = self
+            .state
+            .errors
+            .lock()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))?
+            .iter()
+            .map(|e| Primitive::String(e.to_string()))
+            .collect();
+        Ok(Primitive::Struct(BTreeMap::from([
+            (
+                "running".to_string(),
+                Primitive::Bool(self.state.running.load(Ordering::SeqCst)),
+            ),
+            ("cancelled".to_string(), Primitive::Bool(cancelled)),
+            (
+                "runs".to_string(),
+                Primitive::Int(self.state.runs.load(Ordering::SeqCst) as i128),
+            ),
+            ("errors".to_string(), Primitive::Array(errors)),
+        ])))
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+    time::SystemTime,
+};
+
+use adana_script_core::primitive::Primitive;
+use anyhow::anyhow;
+
+mod parser;
+mod render;
+use parser::{Node, parse};
+use render::Scope;
+pub use render::escape_html;
+
+#[derive(Debug)]
+pub struct Template {
+    nodes: Vec<Node>,
+    /// includes are resolved from there
+    base_dir: PathBuf,
+}
+
+/// Templates loaded from files, compiled again when the file changes on disk.
+static FILE_CACHE: LazyLock<Mutex<FileCache>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+type FileCache = HashMap<PathBuf, (Option<SystemTime>, Arc<Template>)>;
+
+impl Template {
+    fn compile(source: &str, base_dir: PathBuf) -> anyhow::Result<Template> {
+        Ok(Template {
+            nodes: parse(source)?,
+            base_dir,
+        })
+    }
+
+    pub fn render(&self, ctx: &Primitive) -> anyhow::Result<String> {
+        let mut out = String::new();
+        Scope::new(ctx).render(&self.nodes, &self.base_dir, &mut out)?;
+        Ok(out)
+    }
+}
+
+fn load_file(path: &Path) -> anyhow::Result<Arc<Template>> {
+    let modified = std::fs::metadata(path)
+        .map_err(|e| anyhow!("could not read template {}: {e}", path.display()))?
+        .modified()
+        .ok();
+    let mut cache = FILE_CACHE
+        .lock()
+        .map_err(|e| anyhow!("could not acquire lock {e}"))?;
+    if let Some((cached_modified, template)) = cache.get(path) {
+        if modified.is_some() && *cached_modified == modified {
+            return Ok(template.clone());
+        }
+    }
+    let source = std::fs::read_to_string(path)?;
+    let base_dir = path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let template = Arc::new(
+        Template::compile(&source, base_dir).map_err(|e| anyhow!("{}: {e}", path.display()))?,
+    );
+    cache.insert(path.to_path_buf(), (modified, template.clone()));
+    Ok(template)
+}
+
+/// A path to an existing file is loaded (and cached), anything else is the template itself.
+pub fn load(template: &str) -> anyhow::Result<Arc<Template>> {
+    let path = Path::new(template);
+    if !template.contains(['{', '\n']) && path.is_file() {
+        load_file(path)
+    } else {
+        Template::compile(template, std::env::current_dir()?).map(Arc::new)
+    }
+}
+
+/// Renders a template string or a template file with ctx.
+pub fn render_template(template: &str, ctx: &Primitive) -> anyhow::Result<String> {
+    load(template)?.render(ctx)
+}
+
+/// Renders a template string, a template file or a compiled template with ctx.
+pub fn render_value(template: &Primitive, ctx: &Primitive) -> anyhow::Result<String> {
+    match template {
+        Primitive::String(template) => render_template(template, ctx),
+        Primitive::LibData(lib_data) => {
+            let Some(template) = lib_data.data.downcast_ref::<Arc<Template>>() else {
+                return Err(anyhow!(
+                    "invalid libData value. Must be a compiled template"
+                ));
+            };
+            template.render(ctx)
+        }
+        e => Err(anyhow!(
+            "template must be a string or a compiled template => {e}"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use adana_script_core::primitive::Primitive;
+
+    use crate::render_template;
+
+    fn string(s: &str) -> Primitive {
+        Primitive::String(s.to_string())
+    }
+
+    #[test]
+    fn render_variables_loops_conditions() {
+        let ctx = Primitive::Struct(BTreeMap::from([
+            (
+                "user".to_string(),
+                Primitive::Struct(BTreeMap::from([
+                    ("name".to_string(), string("<b>nordine</b>")),
+                    ("role".to_string(), string("admin")),
+                ])),
+            ),
+            (
+                "todos".to_string(),
+                Primitive::Array(vec![string("eat"), string("sleep")]),
+            ),
+            ("empty".to_string(), Primitive::Array(vec![])),
+        ]));
+        let template = r#"{# greeting #}Hi {{ user.name }} {{ user.name | raw }}
+{% for todo in todos %}{{ loop.index }}.{{ todo }}{% if not loop.last %},{% endif %}{% endfor %}
+{% for e in empty %}{{ e }}{% else %}nothing{% endfor %}
+{% if user.role == "guest" %}guest{% elif user.role == "admin" %}admin{% else %}user{% endif %}"#;
+        assert_eq!(
+            render_template(template, &ctx).unwrap(),
+            "Hi &lt;b&gt;nordine&lt;/b&gt; <b>nordine</b>\n1.eat,2.sleep\nnothing\nadmin"
+        );
+
+        let err = render_template("{% if user %}\n{% for x in y %}", &ctx).unwrap_err();
+        assert_eq!(err.to_string(), "missing endfor for the for at line 2");
+    }
+
+    #[test]
+    fn render_file_with_include() {
+        let dir = std::env::temp_dir().join(format!("adana-template-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("header.html"), "<h1>{{ title }}</h1>").unwrap();
+        std::fs::write(
+            dir.join("index.html"),
+            r#"{% include "header.html" %}<p>{{ body }}</p>"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("loop.html"), r#"{% include "loop.html" %}"#).unwrap();
+
+        let ctx = Primitive::Struct(BTreeMap::from([
+            ("title".to_string(), string("adana")),
+            ("body".to_string(), string("hello")),
+        ]));
+        let index = dir.join("index.html").display().to_string();
+        assert_eq!(
+            render_template(&index, &ctx).unwrap(),
+            "<h1>adana</h1><p>hello</p>"
+        );
+        let recursive = dir.join("loop.html").display().to_string();
+        assert!(render_template(&recursive, &ctx).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+use anyhow::anyhow;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Path(Vec<String>),
+    String(String),
+    Int(i128),
+    Double(f64),
+    Bool(bool),
+    Null,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Truthy(Operand),
+    Not(Box<Condition>),
+    Equal(Operand, Operand),
+    NotEqual(Operand, Operand),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Text(String),
+    Variable {
+        path: Vec<String>,
+        raw: bool,
+    },
+    For {
+        name: String,
+        path: Vec<String>,
+        body: Vec<Node>,
+        empty: Vec<Node>,
+    },
+    If {
+        branches: Vec<(Condition, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+    Include(String),
+}
+
+#[derive(Debug)]
+enum Token<'a> {
+    Text(&'a str),
+    Expr(&'a str),
+    Tag(&'a str),
+}
+
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
+
+fn tokenize(source: &str) -> anyhow::Result<Vec<(usize, Token<'_>)>> {
+    let mut tokens = vec![];
+    let mut pos = 0;
+    while let Some(start) = source[pos..].find('{').map(|s| s + pos) {
+        let close = match source[start + 1..].chars().next() {
+            Some('{') => "}}",
+            Some('%') => "%}",
+            Some('#') => "#}",
+            _ => {
+                tokens.push((pos, Token::Text(&source[pos..start + 1])));
+                pos = start + 1;
+                continue;
+            }
+        };
+        if start > pos {
+            tokens.push((pos, Token::Text(&source[pos..start])));
+        }
+        let Some(end) = source[start + 2..].find(close).map(|e| e + start + 2) else {
+            return Err(anyhow!(
+                "unclosed {{{} at line {}",
+                &source[start + 1..start + 2],
+                line_of(source, start)
+            ));
+        };
+        let inner = source[start + 2..end].trim();
+        match close {
+            "}}" => tokens.push((start, Token::Expr(inner))),
+            "%}" => tokens.push((start, Token::Tag(inner))),
+            _ => {} // comment
+        }
+        pos = end + 2;
+    }
+    if pos < source.len() {
+        tokens.push((pos, Token::Text(&source[pos..])));
+    }
+    Ok(tokens)
+}
+
+fn parse_path(path: &str) -> anyhow::Result<Vec<String>> {
+    let segments = path
+        .split('.')
+        .map(|s| s.trim().to_string())
+        .collect::<Vec<_>>();
+    if segments.iter().any(|s| {
+        s.is_empty()
+            || !s
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    }) {
+        return Err(anyhow!("invalid variable {path}"));
+    }
+    Ok(segments)
+}
+
+fn parse_operand(operand: &str) -> anyhow::Result<Operand> {
+    let operand = operand.trim();
+    if let Some(s) = operand
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .or_else(|| {
+            operand
+                .strip_prefix('\'')
+                .and_then(|s| s.strip_suffix('\''))
+        })
+    {
+        return Ok(Operand::String(s.to_string()));
+    }
+    match operand {
+        "true" => return Ok(Operand::Bool(true)),
+        "false" => return Ok(Operand::Bool(false)),
+        "null" => return Ok(Operand::Null),
+        _ => {}
+    }
+    if let Ok(n) = operand.parse::<i128>() {
+        return Ok(Operand::Int(n));
+    }
+    if let Ok(n) = operand.parse::<f64>() {
+        return Ok(Operand::Double(n));
+    }
+    parse_path(operand).map(Operand::Path)
+}
+
+fn parse_condition(condition: &str) -> anyhow::Result<Condition> {
+    let condition = condition.trim();
+    if let Some(negated) = condition.strip_prefix("not ") {
+        return Ok(Condition::Not(Box::new(parse_condition(negated)?)));
+    }
+    if let Some((left, right)) = condition.split_once("!=") {
+        return Ok(Condition::NotEqual(
+            parse_operand(left)?,
+            parse_operand(right)?,
+        ));
+    }
+    if let Some((left, right)) = condition.split_once("==") {
+        return Ok(Condition::Equal(
+            parse_operand(left)?,
+            parse_operand(right)?,
+        ));
+    }
+    parse_operand(condition).map(Condition::Truthy)
+}
+
+/// Nodes, and the closing tag that ended them with its position.
+type Parsed<'a> = (Vec<Node>, Option<(usize, &'a str)>);
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: std::vec::IntoIter<(usize, Token<'a>)>,
+}
+
+impl<'a> Parser<'a> {
+    /// Parses nodes until one of the `until` tags (e.g `endfor`), returned with its position.
+    fn parse_nodes(&mut self, until: &[&str]) -> anyhow::Result<Parsed<'a>> {
+        let mut nodes = vec![];
+        while let Some((offset, token)) = self.tokens.next() {
+            let line = line_of(self.source, offset);
+            match token {
+                Token::Text(text) => nodes.push(Node::Text(text.to_string())),
+                Token::Expr(expr) => {
+                    let (path, raw) = match expr.split_once('|') {
+                        Some((path, filter)) if filter.trim() == "raw" => (path, true),
+                        Some((_, filter)) => {
+                            return Err(anyhow!("unknown filter {} at line {line}", filter.trim()));
+                        }
+                        None => (expr, false),
+                    };
+                    let path =
+                        parse_path(path.trim()).map_err(|e| anyhow!("{e} at line {line}"))?;
+                    nodes.push(Node::Variable { path, raw });
+                }
+                Token::Tag(tag) => {
+                    let (keyword, rest) = tag.split_once(' ').unwrap_or((tag, ""));
+                    if until.contains(&keyword) {
+                        return Ok((nodes, Some((offset, tag))));
+                    }
+                    let node = match keyword {
+                        "for" => self.parse_for(rest, line)?,
+                        "if" => self.parse_if(rest, line)?,
+                        "include" => match parse_operand(rest) {
+                            Ok(Operand::String(path)) => Node::Include(path),
+                            _ => {
+                                return Err(anyhow!(
+                                    r#"include expects a quoted path (e.g include "header.html") at line {line}"#
+                                ));
+                            }
+                        },
+                        _ => return Err(anyhow!("unexpected tag {tag} at line {line}")),
+                    };
+                    nodes.push(node);
+                }
+            }
+        }
+        Ok((nodes, None))
+    }
+
+    fn parse_for(&mut self, rest: &str, line: usize) -> anyhow::Result<Node> {
+        let Some((name, path)) = rest.split_once(" in ") else {
+            return Err(anyhow!("for expects `for item in items` at line {line}"));
+        };
+        let name = name.trim().to_string();
+        let path = parse_path(path.trim()).map_err(|e| anyhow!("{e} at line {line}"))?;
+        let (body, end) = self.parse_nodes(&["else", "endfor"])?;
+        let empty = match end {
+            Some((_, "else")) => match self.parse_nodes(&["endfor"])? {
+                (empty, Some(_)) => empty,
+                (_, None) => return Err(anyhow!("missing endfor for the for at line {line}")),
+            },
+            Some(_) => vec![],
+            None => return Err(anyhow!("missing endfor for the for at line {line}")),
+        };
+        Ok(Node::For {
+            name,
+            path,
+            body,
+            empty,
+        })
+    }
+
+    fn parse_if(&mut self, rest: &str, line: usize) -> anyhow::Result<Node> {
+        let mut branches = vec![];
+        let mut condition = parse_condition(rest).map_err(|e| anyhow!("{e} at line {line}"))?;
+        loop {
+            let (body, end) = self.parse_nodes(&["elif", "else", "endif"])?;
+            branches.push((condition, body));
+            match end {
+                Some((offset, tag)) if tag.starts_with("elif") => {
+                    condition = parse_condition(tag.trim_start_matches("elif"))
+                        .map_err(|e| anyhow!("{e} at line {}", line_of(self.source, offset)))?;
+                }
+                Some((_, "else")) => {
+                    let (otherwise, end) = self.parse_nodes(&["endif"])?;
+                    if end.is_none() {
+                        return Err(anyhow!("missing endif for the if at line {line}"));
+                    }
+                    return Ok(Node::If {
+                        branches,
+                        otherwise,
+                    });
+                }
+                Some(_) => {
+                    return Ok(Node::If {
+                        branches,
+                        otherwise: vec![],
+                    });
+                }
+                None => return Err(anyhow!("missing endif for the if at line {line}")),
+            }
+        }
+    }
+}
+
+pub fn parse(source: &str) -> anyhow::Result<Vec<Node>> {
+    let mut parser = Parser {
+        source,
+        tokens: tokenize(source)?.into_iter(),
+    };
+    // stray closing tags are rejected, as they are not expected at the top level
+    let (nodes, _) = parser.parse_nodes(&[])?;
+    Ok(nodes)
+}
@@ -0,0 +1,201 @@
+use std::{collections::BTreeMap, path::Path};
+
+use adana_script_core::primitive::Primitive;
+use anyhow::anyhow;
+
+use crate::{
+    load_file,
+    parser::{Condition, Node, Operand},
+};
+
+/// Guards against templates including themselves.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+pub struct Scope<'a> {
+    ctx: &'a Primitive,
+    /// loop variables, innermost last
+    locals: Vec<(String, Primitive)>,
+    depth: usize,
+}
+
+fn deref(p: &Primitive) -> Primitive {
+    match p {
+        Primitive::Ref(r) => r.read().map(|r| deref(&r)).unwrap_or(Primitive::Null),
+        p => p.clone(),
+    }
+}
+
+fn field(p: &Primitive, name: &str) -> Primitive {
+    match deref(p) {
+        Primitive::Struct(s) => s.get(name).map(deref).unwrap_or(Primitive::Null),
+        Primitive::Array(a) => name
+            .parse::<usize>()
+            .ok()
+            .and_then(|idx| a.get(idx))
+            .map(deref)
+            .unwrap_or(Primitive::Null),
+        _ => Primitive::Null,
+    }
+}
+
+fn is_truthy(p: &Primitive) -> bool {
+    match p {
+        Primitive::Null | Primitive::Unit | Primitive::NoReturn | Primitive::Bool(false) => false,
+        Primitive::String(s) => !s.is_empty(),
+        Primitive::Array(a) => !a.is_empty(),
+        Primitive::Struct(s) => !s.is_empty(),
+        Primitive::Int(n) => *n != 0,
+        Primitive::U8(n) => *n != 0,
+        Primitive::I8(n) => *n != 0,
+        Primitive::Double(n) => *n != 0.,
+        Primitive::Ref(_) => is_truthy(&deref(p)),
+        _ => true,
+    }
+}
+
+fn to_text(p: &Primitive) -> String {
+    match p {
+        Primitive::String(s) => s.to_string(),
+        Primitive::Null | Primitive::Unit | Primitive::NoReturn => String::new(),
+        p => p.to_string(),
+    }
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl<'a> Scope<'a> {
+    pub fn new(ctx: &'a Primitive) -> Scope<'a> {
+        Scope {
+            ctx,
+            locals: vec![],
+            depth: 0,
+        }
+    }
+
+    fn lookup(&self, path: &[String]) -> Primitive {
+        let Some((first, rest)) = path.split_first() else {
+            return Primitive::Null;
+        };
+        let root = self
+            .locals
+            .iter()
+            .rev()
+            .find(|(name, _)| name == first)
+            .map(|(_, value)| value.clone())
+            .unwrap_or_else(|| field(self.ctx, first));
+        rest.iter().fold(root, |value, name| field(&value, name))
+    }
+
+    fn operand(&self, operand: &Operand) -> Primitive {
+        match operand {
+            Operand::Path(path) => self.lookup(path),
+            Operand::String(s) => Primitive::String(s.to_string()),
+            Operand::Int(n) => Primitive::Int(*n),
+            Operand::Double(n) => Primitive::Double(*n),
+            Operand::Bool(b) => Primitive::Bool(*b),
+            Operand::Null => Primitive::Null,
+        }
+    }
+
+    fn test(&self, condition: &Condition) -> bool {
+        match condition {
+            Condition::Truthy(operand) => is_truthy(&self.operand(operand)),
+            Condition::Not(condition) => !self.test(condition),
+            Condition::Equal(left, right) => matches!(
+                self.operand(left).is_equal(&self.operand(right)),
+                Primitive::Bool(true)
+            ),
+            Condition::NotEqual(left, right) => !matches!(
+                self.operand(left).is_equal(&self.operand(right)),
+                Primitive::Bool(true)
+            ),
+        }
+    }
+
+    /// `base_dir` is where includes are resolved from.
+    pub fn render(
+        &mut self,
+        nodes: &[Node],
+        base_dir: &Path,
+        out: &mut String,
+    ) -> anyhow::Result<()> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Variable { path, raw } => {
+                    let text = to_text(&self.lookup(path));
+                    if *raw {
+                        out.push_str(&text);
+                    } else {
+                        out.push_str(&escape_html(&text));
+                    }
+                }
+                Node::For {
+                    name,
+                    path,
+                    body,
+                    empty,
+                } => {
+                    let items = match self.lookup(path) {
+                        Primitive::Array(items) => items,
+                        Primitive::Null => vec![],
+                        e => return Err(anyhow!("{} is not an array => {e}", path.join("."))),
+                    };
+                    if items.is_empty() {
+                        self.render(empty, base_dir, out)?;
+                    }
+                    let len = items.len();
+                    for (idx, item) in items.into_iter().enumerate() {
+                        let state = Primitive::Struct(BTreeMap::from([
+                            ("index".to_string(), Primitive::Int(idx as i128 + 1)),
+                            ("index0".to_string(), Primitive::Int(idx as i128)),
+                            ("first".to_string(), Primitive::Bool(idx == 0)),
+                            ("last".to_string(), Primitive::Bool(idx + 1 == len)),
+                            ("length".to_string(), Primitive::Int(len as i128)),
+                        ]));
+                        self.locals.push(("loop".to_string(), state));
+                        self.locals.push((name.to_string(), deref(&item)));
+                        let res = self.render(body, base_dir, out);
+                        self.locals.truncate(self.locals.len() - 2);
+                        res?;
+                    }
+                }
+                Node::If {
+                    branches,
+                    otherwise,
+                } => {
+                    let branch = branches
+                        .iter()
+                        .find(|(condition, _)| self.test(condition))
+                        .map(|(_, body)| body)
+                        .unwrap_or(otherwise);
+                    self.render(branch, base_dir, out)?;
+                }
+                Node::Include(path) => {
+                    if self.depth >= MAX_INCLUDE_DEPTH {
+                        return Err(anyhow!("too many nested includes ({path})"));
+                    }
+                    let template = load_file(&base_dir.join(path))?;
+                    self.depth += 1;
+                    let res = self.render(&template.nodes, &template.base_dir, out);
+                    self.depth -= 1;
+                    res?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use adana_script_core::primitive::{Compiler, LibData, NativeFunctionCallResult, Primitive};
+use adana_std_template_engine::{load, render_value};
+use anyhow::anyhow;
+
+#[unsafe(no_mangle)]
+pub fn compile(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    let Some(Primitive::String(template)) = params.first() else {
+        return Err(anyhow!(
+            "invalid param (e.g compile(\"<p>{{{{ name }}}}</p>\") or compile(\"index.html\"))"
+        ));
+    };
+    Ok(Primitive::LibData(LibData {
+        data: Arc::new(Box::new(load(template)?)),
+    }))
+}
+
+#[unsafe(no_mangle)]
+pub fn render(params: Vec<Primitive>, _compiler: Box<Compiler>) -> NativeFunctionCallResult {
+    if params.is_empty() || params.len() > 2 {
+        return Err(anyhow!(
+            "invalid param (e.g render(template, struct {{name: \"adana\"}}))"
+        ));
+    }
+    let ctx = params.get(1).cloned().unwrap_or(Primitive::Null);
+    let template = match &params[0] {
+        Primitive::Ref(r) => r
+            .read()
+            .map_err(|e| anyhow!("could not acquire lock {e}"))?
+            .clone(),
+        template => template.clone(),
+    };
+    Ok(Primitive::String(render_value(&template, &ctx)?))
+}